@@ -14,11 +14,13 @@ document.getElementById("input_caption").addEventListener("keyup", function(even
         url.hash = "";
         url.pathname = url.pathname + location.hash.substr(1);
         var ic = document.getElementById("input_caption");
-        var post_url = url.toString() + "?caption=" + encodeURI(ic.value) + "&crumb=" + crumb;
+        var post_url = url.toString();
 
         hideCaptionEdit();
         fetch(post_url, {
-            method: "POST"
+            method: "POST",
+            headers: { "X-CSRF-Token": csrfToken, "Content-Type": "application/json" },
+            body: JSON.stringify({ caption: ic.value })
         }).then(response => {
             if (response.status != 200) {
                 response.text().then(text => alert("Unable to update caption: " + text));
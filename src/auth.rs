@@ -0,0 +1,275 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use hmac::{ Hmac, Mac };
+
+use once_cell::sync::Lazy;
+
+use sha2::Sha256;
+
+use subtle::ConstantTimeEq;
+
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Slow enough to make guessing from a leaked hash expensive, but fast enough to check on login
+const PBKDF2_ROUNDS: u32 = 100_000;
+const HASH_LEN: usize = 32;
+
+// Checked against when there's no such editor, so a wrong name takes as long as a wrong password
+pub static DUMMY_HASH: Lazy<PasswordHash> = Lazy::new(|| PasswordHash::new(""));
+
+pub static SESSION_COOKIE: &str = "gallery_session";
+pub static SHARE_COOKIE: &str = "gallery_share";
+
+// Shorter secrets could be guessed from a link
+const MIN_SECRET_LEN: usize = 16;
+
+// How long a login lasts
+pub const SESSION_DURATION: u64 = 30 * 24 * 60 * 60;
+
+// A password as given to rust_gallery_password, e.g. 'pbkdf2-sha256:100000:<salt>:<hash>' with
+// the salt and hash in hex. Written by 'make-gallery hash-password'.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordHash {
+    rounds: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> PasswordHash {
+        let salt = Uuid::new_v4().as_bytes().to_vec();
+        let hash = pbkdf2(password, &salt, PBKDF2_ROUNDS);
+
+        PasswordHash { rounds: PBKDF2_ROUNDS, salt, hash }
+    }
+
+    pub fn parse(s: &str) -> Option<PasswordHash> {
+        let mut parts = s.trim().split(':');
+        if parts.next()? != "pbkdf2-sha256" {
+            return None;
+        }
+        let rounds = parts.next()?.parse::<u32>().ok().filter(|r| *r > 0)?;
+        let salt = hex::decode(parts.next()?).ok()?;
+        let hash = hex::decode(parts.next()?).ok().filter(|h| h.len() == HASH_LEN)?;
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(PasswordHash { rounds, salt, hash })
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        pbkdf2(password, &self.salt, self.rounds).ct_eq(&self.hash).into()
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pbkdf2-sha256:{}:{}:{}", self.rounds, hex::encode(&self.salt), hex::encode(&self.hash))
+    }
+}
+
+fn pbkdf2(password: &str, salt: &[u8], rounds: u32) -> Vec<u8> {
+    let mut hash = vec![0u8; HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+
+    hash
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Sessions are signed with password hashes, which only the server has, so every nginx worker can
+// check them. Viewers' sessions use the passwords, so changing one signs every viewer out, and each
+// editor's uses their own hash, so changing theirs signs just them out.
+pub fn session_key<'a>(passwords: impl Iterator<Item = &'a PasswordHash>) -> Vec<u8> {
+    let mut key = Vec::new();
+    for p in passwords {
+        key.extend_from_slice(&p.hash);
+    }
+
+    key
+}
+
+// Editors have a name, which is letters, digits, '-' and '_' so it can be in the session cookie.
+pub fn is_valid_user(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// A line of an editors file, 'name:hash'
+pub fn parse_editor(line: &str) -> Option<(String, PasswordHash)> {
+    let (name, hash) = line.trim().split_once(':')?;
+    if !is_valid_user(name) {
+        return None;
+    }
+
+    Some((name.to_string(), PasswordHash::parse(hash)?))
+}
+
+fn sign(key: &[u8], purpose: &str, value: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes any size of key");
+    mac.update(purpose.as_bytes());
+    mac.update(b":");
+    mac.update(value.as_bytes());
+
+    mac
+}
+
+// The value of the session cookie, '<expiry>.<user>.<signature>'. The user is empty for those
+// who signed in with a password rather than as an editor.
+pub fn make_session(key: &[u8], expires: u64, user: &str) -> String {
+    let value = format!("{}.{}", expires, user);
+    let signature = hex::encode(sign(key, "session", &value).finalize().into_bytes());
+
+    format!("{}.{}", value, signature)
+}
+
+// Returns the user of a valid session, which is empty if they aren't an editor. 'key' gives the key
+// a user's sessions are signed with, or None if they can't sign in.
+pub fn session_user(key: impl Fn(&str) -> Option<Vec<u8>>, session: &str, now: u64) -> Option<&str> {
+    let (value, signature) = session.rsplit_once('.')?;
+    let (expires, user) = value.split_once('.')?;
+    let signature = hex::decode(signature).ok()?;
+    if expires.parse::<u64>().ok()? <= now {
+        return None;
+    }
+
+    sign(&key(user)?, "session", value).verify_slice(&signature).ok()?;
+    Some(user)
+}
+
+// The token edit_caption.js sends back in X-CSRF-Token. It's signed with the crumb cookie, which
+// other sites can neither read nor send, and covers the session so it changes with the user.
+pub fn csrf_token(crumb: &str, session: &str) -> String {
+    hex::encode(sign(crumb.as_bytes(), "csrf", session).finalize().into_bytes())
+}
+
+pub fn is_valid_csrf_token(crumb: &str, session: &str, token: &str) -> bool {
+    !crumb.is_empty() && csrf_token(crumb, session).as_bytes().ct_eq(token.as_bytes()).into()
+}
+
+// The secret share links are signed with, shared by nginx and 'make-gallery share'.
+pub fn read_secret(path: &Path) -> Result<Vec<u8>, String> {
+    let secret = fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    let secret = secret.trim();
+    if secret.len() < MIN_SECRET_LEN {
+        return Err(format!("The secret in {} must be at least {} characters", path.display(), MIN_SECRET_LEN));
+    }
+
+    Ok(secret.as_bytes().to_vec())
+}
+
+// The 'sig' of a share link for 'path', which is as nginx decodes it, e.g. '/gallery/iceland/' for a
+// gallery or '/gallery/iceland/12.jpg' for a photo.
+pub fn share_signature(secret: &[u8], path: &str, expires: u64) -> String {
+    hex::encode(sign(secret, "share", &format!("{}:{}", path, expires)).finalize().into_bytes())
+}
+
+pub fn is_valid_share(secret: &[u8], path: &str, expires: &str, signature: &str, now: u64) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(s) => s,
+        Err(_) => return false
+    };
+    match expires.parse::<u64>() {
+        Ok(e) if e > now => sign(secret, "share", &format!("{}:{}", path, expires)).verify_slice(&signature).is_ok(),
+        _ => false
+    }
+}
+
+// Whether any of the share cookies, '<expiry>.<signature>', is for a directory above 'path'. A gallery's
+// cookie is for its path, and its albums are beneath it.
+pub fn is_shared(secret: &[u8], path: &str, cookies: &[&str], now: u64) -> bool {
+    let shares: Vec<(&str, &str)> = cookies.iter().filter_map(|s| s.split_once('.')).collect();
+    path.match_indices('/').any(|(i, _)| {
+        shares.iter().any(|(expires, signature)| is_valid_share(secret, &path[..=i], expires, signature, now))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+    const NOW: u64 = 1_700_000_000;
+
+    fn share_cookie(path: &str, expires: u64) -> String {
+        format!("{}.{}", expires, share_signature(SECRET, path, expires))
+    }
+
+    #[test]
+    fn share_is_valid_until_it_expires() {
+        let signature = share_signature(SECRET, "/gallery/iceland/", NOW + 60);
+        let expires = (NOW + 60).to_string();
+        assert!(is_valid_share(SECRET, "/gallery/iceland/", &expires, &signature, NOW));
+        assert!(is_valid_share(SECRET, "/gallery/iceland/", &expires, &signature, NOW + 59));
+        assert!(!is_valid_share(SECRET, "/gallery/iceland/", &expires, &signature, NOW + 60));
+        assert!(!is_valid_share(SECRET, "/gallery/iceland/", "soon", &signature, NOW));
+    }
+
+    #[test]
+    fn share_rejects_tampering() {
+        let signature = share_signature(SECRET, "/gallery/iceland/12.jpg", NOW + 60);
+        let expires = (NOW + 60).to_string();
+        assert!(!is_valid_share(SECRET, "/gallery/iceland/13.jpg", &expires, &signature, NOW));
+        assert!(!is_valid_share(SECRET, "/gallery/iceland/12.jpg", &(NOW + 61).to_string(), &signature, NOW));
+        assert!(!is_valid_share(b"another secret that is long", "/gallery/iceland/12.jpg", &expires, &signature, NOW));
+        assert!(!is_valid_share(SECRET, "/gallery/iceland/12.jpg", &expires, &signature[2..], NOW));
+        assert!(!is_valid_share(SECRET, "/gallery/iceland/12.jpg", &expires, "not hex", NOW));
+    }
+
+    #[test]
+    fn shared_gallery_covers_what_is_beneath_it() {
+        let cookie = share_cookie("/gallery/iceland/", NOW + 60);
+        let cookies = [cookie.as_str()];
+        assert!(is_shared(SECRET, "/gallery/iceland/", &cookies, NOW));
+        assert!(is_shared(SECRET, "/gallery/iceland/12.jpg", &cookies, NOW));
+        assert!(is_shared(SECRET, "/gallery/iceland/reykjavik/3.jpg", &cookies, NOW));
+        assert!(!is_shared(SECRET, "/gallery/", &cookies, NOW));
+        assert!(!is_shared(SECRET, "/gallery/iceland2/1.jpg", &cookies, NOW));
+        assert!(!is_shared(SECRET, "/gallery/iceland/12.jpg", &cookies, NOW + 60));
+    }
+
+    #[test]
+    fn shared_gallery_checks_every_cookie() {
+        let expired = share_cookie("/gallery/iceland/", NOW - 1);
+        let other = share_cookie("/gallery/norway/", NOW + 60);
+        let valid = share_cookie("/gallery/iceland/", NOW + 60);
+        assert!(!is_shared(SECRET, "/gallery/iceland/1.jpg", &[expired.as_str(), other.as_str(), "junk"], NOW));
+        assert!(is_shared(SECRET, "/gallery/iceland/1.jpg", &[expired.as_str(), "junk", valid.as_str()], NOW));
+        assert!(!is_shared(SECRET, "/gallery/iceland/1.jpg", &[], NOW));
+    }
+
+    #[test]
+    fn photo_share_is_not_a_gallery_share() {
+        let cookie = share_cookie("/gallery/iceland/12.jpg", NOW + 60);
+        assert!(!is_shared(SECRET, "/gallery/iceland/13.jpg", &[cookie.as_str()], NOW));
+    }
+
+    #[test]
+    fn session_user_checks_expiry_and_signature() {
+        let key = b"viewer key".to_vec();
+        let session = make_session(&key, NOW + 60, "");
+        assert_eq!(session_user(|_| Some(key.clone()), &session, NOW), Some(""));
+        assert_eq!(session_user(|_| Some(key.clone()), &session, NOW + 60), None);
+        assert_eq!(session_user(|_| Some(b"other key".to_vec()), &session, NOW), None);
+        assert_eq!(session_user(|_| None, &session, NOW), None);
+    }
+
+    #[test]
+    fn session_user_uses_the_user_key() {
+        let jane = b"jane's hash".to_vec();
+        let key = |u: &str| if u == "jane" { Some(jane.clone()) } else { None };
+        let session = make_session(&jane, NOW + 60, "jane");
+        assert_eq!(session_user(key, &session, NOW), Some("jane"));
+        assert_eq!(session_user(key, &session.replacen("jane", "joe", 1), NOW), None);
+        assert_eq!(session_user(key, &session.replacen(&(NOW + 60).to_string(), &(NOW + 600).to_string(), 1), NOW), None);
+        assert_eq!(session_user(key, "junk", NOW), None);
+        assert_eq!(session_user(key, "", NOW), None);
+    }
+}
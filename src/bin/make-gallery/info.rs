@@ -0,0 +1,65 @@
+use std::fs;
+
+use serde::Deserialize;
+
+use rust_gallery::GalleryInfo;
+use rust_gallery::Image;
+
+pub static INFO_FILE: &str = "gallery.toml";
+
+// The cover may be given by id, as in the gallery's urls, or by file name, which doesn't
+// change as photos are added.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Cover {
+    Id(usize),
+    Path(String)
+}
+
+// What can be set in gallery.toml, e.g.
+//
+//   title = "Iceland"
+//   description = "Two weeks driving the ring road"
+//   author = "Jane"
+//   cover = "IMG_1234.jpg"
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct InfoFile {
+    title: Option<String>,
+    description: Option<String>,
+    author: Option<String>,
+    cover: Option<Cover>
+}
+
+// Reads gallery.toml, if there is one, and adds the date range of the items.
+pub fn read_gallery_info(images: &Vec<Image>) -> Result<GalleryInfo, String> {
+    let file = match fs::read_to_string(INFO_FILE) {
+        Ok(s) => toml::from_str::<InfoFile>(&s).map_err(|e| format!("{} is not valid: {}", INFO_FILE, e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => InfoFile::default(),
+        Err(e) => return Err(format!("Unable to read {}: {}", INFO_FILE, e))
+    };
+
+    let cover = match file.cover {
+        Some(Cover::Id(id)) => {
+            if id == 0 || id > images.len() {
+                return Err(format!("The cover in {} is {}, but the ids are 1 to {}", INFO_FILE, id, images.len()));
+            }
+            Some(id)
+        },
+        Some(Cover::Path(path)) => match images.iter().position(|i| i.path == path) {
+            Some(index) => Some(index + 1),
+            None => return Err(format!("The cover in {} is {}, which isn't in the gallery", INFO_FILE, path))
+        },
+        None => None
+    };
+
+    Ok(GalleryInfo {
+        title: file.title,
+        description: file.description,
+        author: file.author,
+        cover,
+        start: images.iter().map(|i| i.time).min(),
+        end: images.iter().map(|i| i.time).max(),
+        ..GalleryInfo::default()
+    })
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
 
 use std::env;
 use std::fs;
@@ -7,99 +7,635 @@ use std::fs::File;
 use std::io::{ prelude::*, BufReader };
 
 use std::include_str;
-use std::path::PathBuf;
+use std::path::{ Path, PathBuf };
 use std::process::{ Command, exit, Output };
-use std::str::FromStr;
 use std::vec::Vec;
 
 use chrono::DateTime;
 use chrono::NaiveDateTime;
 
-use serde_json;
+use clap::{ Args, CommandFactory, Parser, Subcommand };
+use clap::error::ErrorKind;
+
+use rayon::prelude::*;
 
 use rust_gallery;
 
 use rust_gallery::MD_FILE;
+use rust_gallery::Album;
 use rust_gallery::Image;
+use rust_gallery::Gallery;
+use rust_gallery::GalleryInfo;
+use rust_gallery::load_metadata;
+use rust_gallery::save_metadata;
+use rust_gallery::save_metadata_keeping_captions;
+use rust_gallery::MediaKind;
 use rust_gallery::make_preview;
+use rust_gallery::file_arg;
+use rust_gallery::Progress;
+use rust_gallery::parse_filter;
+use rust_gallery::PasswordHash;
+use rust_gallery::read_secret;
+use rust_gallery::is_valid_user;
+use rust_gallery::share_signature;
+use rust_gallery::ResizeOptions;
+
+mod info;
+mod metadata;
+mod video;
+
+use info::read_gallery_info;
+use metadata::{ file_stamp, modified_time, read_metadata };
+
+/// Prepares a directory of photos and videos to be served by the rust_gallery nginx module.
+/// Subdirectories with photos or videos are albums of the gallery, and are prepared too.
+/// With no command everything is built, as with 'build'.
+#[derive(Parser)]
+#[command(name = "make-gallery")]
+struct Cli {
+    /// The directory with the media, rather than the current directory
+    #[arg(long, global = true, value_name = "DIR")]
+    dir: Option<PathBuf>,
+
+    /// Print what would be written without writing anything
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// How many photos and videos to process at once, by default the number of cores
+    #[arg(short, long, global = true, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: Option<u16>,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    // The options of 'build', for when there's no command
+    #[command(flatten)]
+    build: BuildArgs
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Write the metadata, thumbnails, video previews, downscaled videos and html
+    Build(BuildArgs),
+    /// Only write the metadata file
+    Metadata(MetadataArgs),
+    /// Write the thumbnails and video previews from the existing metadata
+    Thumbnails(ImageArgs),
+    /// Downscale videos that browsers can't play or that are too large, and update the metadata
+    Videos,
+    /// Write the html and javascript served with the gallery
+    Html,
+    /// Check that the gallery has everything needed to be served
+    Validate,
+    /// Print the hash of a password, read from standard input, for nginx's rust_gallery_password
+    /// or, with --user, a line of rust_gallery_editors_file
+    HashPassword(HashPasswordArgs),
+    /// Print a link to a gallery or photo that works without a password until it expires
+    Share(ShareArgs)
+}
+
+#[derive(Args)]
+struct BuildArgs {
+    #[command(flatten)]
+    sort: SortArgs,
+
+    /// Only process media that's new or changed since the existing metadata was written
+    #[arg(short, long)]
+    incremental: bool,
+
+    #[command(flatten)]
+    image: ImageArgs
+}
+
+#[derive(Args)]
+struct MetadataArgs {
+    #[command(flatten)]
+    sort: SortArgs,
+
+    /// Only read media that's new or changed since the existing metadata was written
+    #[arg(short, long)]
+    incremental: bool,
+
+    /// Write the metadata here rather than to the gallery, e.g. to check it before replacing the existing one.
+    /// The metadata of albums isn't written.
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>
+}
+
+#[derive(Args)]
+struct HashPasswordArgs {
+    /// The name of the editor the password is for
+    #[arg(short, long, value_name = "NAME")]
+    user: Option<String>
+}
+
+#[derive(Args)]
+struct ShareArgs {
+    /// The url, or just the path, of the gallery, e.g. /gallery/iceland/, or of a photo, e.g. /gallery/iceland/12.jpg
+    url: String,
+
+    /// The file with the secret, as given to nginx's rust_gallery_share_secret_file
+    #[arg(short, long, value_name = "FILE")]
+    secret_file: PathBuf,
+
+    /// How many days the link works for
+    #[arg(long, default_value_t = 7, value_parser = clap::value_parser!(u64).range(1..))]
+    days: u64
+}
+
+// Photos are sorted by their exif date unless one of these is given
+#[derive(Args)]
+#[group(multiple = false)]
+struct SortArgs {
+    /// Sort by filename in date-time format (YYYYmmddHHMMSS) rather than exif
+    #[arg(short = 'd', long)]
+    filename_date: bool,
+
+    /// Sort by filename in numerical format rather than exif
+    #[arg(short = 'n', long)]
+    filename_number: bool,
+
+    /// Don't sort, i.e. keep the order of the file names
+    #[arg(short = 'x', long)]
+    no_sort: bool
+}
+
+#[derive(Args)]
+struct ImageArgs {
+    /// The jpg quality of thumbnails and video previews
+    #[arg(short, long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: Option<u8>,
+
+    /// The resize filter of video previews
+    #[arg(short, long, value_parser = ["nearest", "triangle", "catmullrom", "gaussian", "lanczos3"])]
+    filter: Option<String>
+}
+
+impl BuildArgs {
+    fn is_set(&self) -> bool {
+        self.sort.filename_date || self.sort.filename_number || self.sort.no_sort || self.incremental ||
+        self.image.quality.is_some() || self.image.filter.is_some()
+    }
+}
+
+impl ImageArgs {
+    fn resize_options(&self) -> ResizeOptions {
+        let mut options = ResizeOptions::default();
+        if let Some(q) = self.quality {
+            options.quality = q;
+        }
+        if let Some(ref f) = self.filter {
+            options.filter = parse_filter(f).expect("Filter was validated by clap");
+        }
+
+        options
+    }
+}
 
 fn main() {
-    let mut date_srt = false;
-    let mut num_srt = false;
-    let mut no_srt = false;
-    let mut metadata_only = false;
-
-    let args: Vec<String> = env::args().collect();
-    for arg in args {
-        if arg == "-h" {
-            println!("Run from the directory with the media.");
-            println!("\tTo sort by filename in date-time format rather than exif use '-d'");
-            println!("\tTo sort by filename in numerical format rather than exif use '-n'");
-            println!("\tTo sort by modified times of the files rather than exif use '-x'");
-            println!("\tTo write the metadata file to the temporary directory and do no other processing use '-m'");
-            return;
+    let cli = Cli::parse();
+
+    // clap's args_conflicts_with_subcommands would also reject the global options before a command
+    if cli.command.is_some() && cli.build.is_set() {
+        Cli::command().error(ErrorKind::ArgumentConflict, "options of 'build' go after the command, e.g. 'make-gallery build -d'").exit();
+    }
+
+    if let Some(ref dir) = cli.dir {
+        if let Err(e) = env::set_current_dir(dir) {
+            println!("Unable to use {} as the gallery directory: {}", dir.display(), e);
+            exit(1);
         }
-        if arg == "-d" {
-            date_srt = true;
+    }
+
+    if let Some(jobs) = cli.jobs {
+        rayon::ThreadPoolBuilder::new().num_threads(jobs as usize).build_global().expect("Thread pool is only built once");
+    }
+
+    let dry_run = cli.dry_run;
+    match cli.command {
+        None => { build(&cli.build, None, dry_run); },
+        Some(Commands::Build(args)) => { build(&args, None, dry_run); },
+        Some(Commands::Metadata(args)) => {
+            let output = args.output.clone().unwrap_or(PathBuf::from(MD_FILE));
+            write_metadata(&args, &output, None, dry_run);
+        },
+        Some(Commands::Thumbnails(args)) => thumbnails(&args.resize_options(), dry_run),
+        Some(Commands::Videos) => videos(dry_run),
+        Some(Commands::Html) => html(dry_run),
+        Some(Commands::Validate) => {
+            if !validate() {
+                exit(1);
+            }
+        },
+        Some(Commands::HashPassword(args)) => hash_password(args.user.as_deref()),
+        Some(Commands::Share(args)) => share(&args)
+    }
+}
+
+fn share(args: &ShareArgs) {
+    let secret = match read_secret(&args.secret_file) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("{}", e);
+            exit(1);
         }
-        if arg == "-n" {
-            num_srt = true;
+    };
+
+    // The signature is of the path as nginx decodes it
+    let url = args.url.split(['?', '#']).next().unwrap_or("");
+    let (origin, path) = match url.find("://") {
+        Some(i) => match url[i + 3..].find('/') {
+            Some(j) => url.split_at(i + 3 + j),
+            None => (url, "/")
+        },
+        None => ("", url)
+    };
+    let mut path = match urlencoding::decode(path) {
+        Ok(p) => p.into_owned(),
+        Err(_) => {
+            println!("{} isn't a valid path", path);
+            exit(1);
         }
-        if arg == "-x" {
-            no_srt = true;
+    };
+    if !path.starts_with('/') {
+        path.insert(0, '/');
+    }
+    // A gallery, rather than a photo or video, is a directory
+    if !path.ends_with('/') && MediaKind::from_path(&path).is_none() {
+        path.push('/');
+    }
+
+    let expires = chrono::Utc::now().timestamp() as u64 + args.days * 24 * 60 * 60;
+    let encoded: Vec<String> = path.split('/').map(|s| urlencoding::encode(s).into_owned()).collect();
+    println!("{}{}?exp={}&sig={}", origin, encoded.join("/"), expires, share_signature(&secret, &path, expires));
+    println!("The link expires on {}", DateTime::from_timestamp(expires as i64, 0).unwrap().with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"));
+}
+
+fn hash_password(user: Option<&str>) {
+    if let Some(user) = user {
+        if !is_valid_user(user) {
+            println!("Names may only have letters, digits, '-' and '_'");
+            exit(1);
         }
-        if arg == "-m" {
-            metadata_only = true;
+    }
+
+    let mut password = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut password) {
+        println!("Unable to read the password: {}", e);
+        exit(1);
+    }
+
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        println!("The password is empty");
+        exit(1);
+    }
+    match user {
+        Some(user) => println!("{}:{}", user, PasswordHash::new(password)),
+        None => println!("{}", PasswordHash::new(password))
+    }
+}
+
+// Builds the gallery in the current directory, and its albums, returning its metadata.
+fn build(args: &BuildArgs, parent: Option<&str>, dry_run: bool) -> Gallery {
+    let read = read_captions();
+    let (mut images, previous) = read_sorted_media(&args.sort, args.incremental, true, dry_run);
+    // Before the slow part, so mistakes in gallery.toml are reported straight away
+    let mut info = gallery_info(&images, parent);
+
+    let title = gallery_title(&info);
+    let albums = in_albums(&find_albums(), |album| Album::new(album, &build(args, Some(&title), dry_run)));
+    add_albums(&mut info, albums, &args.sort);
+
+    make_thumbnails(&images, &previous, &args.image.resize_options(), dry_run);
+    downscale_videos(&mut images, &previous, dry_run);
+
+    // Saved after downscaling so it records which videos were scaled
+    let mut gallery = Gallery::new(info, images);
+    save_gallery(&mut gallery, Path::new(MD_FILE), &read, dry_run);
+    save_html(dry_run);
+
+    gallery
+}
+
+fn write_metadata(args: &MetadataArgs, output: &Path, parent: Option<&str>, dry_run: bool) -> Gallery {
+    let read = read_captions();
+    // The previews aren't regenerated, so keep them until 'make-gallery thumbnails' replaces them
+    let (images, _) = read_sorted_media(&args.sort, args.incremental, false, dry_run);
+    let mut info = gallery_info(&images, parent);
+
+    // With --output the existing metadata is being checked, so leave the albums' alone
+    let title = gallery_title(&info);
+    let albums_dry_run = dry_run || args.output.is_some();
+    let albums = in_albums(&find_albums(), |album| Album::new(album, &write_metadata(args, Path::new(MD_FILE), Some(&title), albums_dry_run)));
+    add_albums(&mut info, albums, &args.sort);
+
+    let mut gallery = Gallery::new(info, images);
+    save_gallery(&mut gallery, output, &read, dry_run);
+
+    gallery
+}
+
+fn thumbnails(options: &ResizeOptions, dry_run: bool) {
+    let gallery = load_existing_metadata();
+    make_thumbnails(&gallery.items, &vec![None; gallery.items.len()], options, dry_run);
+    in_albums(&album_paths(&gallery), |_| thumbnails(options, dry_run));
+}
+
+fn videos(dry_run: bool) {
+    let gallery = load_existing_metadata();
+    in_albums(&album_paths(&gallery), |_| videos(dry_run));
+
+    let mut info = gallery_info(&gallery.items, gallery.gallery.parent.as_deref());
+    // Downscaling doesn't change the albums' summaries
+    info.albums = gallery.gallery.albums;
+    add_dates(&mut info);
+
+    let read = gallery.items.iter().map(|i| (i.path.clone(), i.caption.clone())).collect();
+    let mut images = gallery.items;
+    let previous = vec![None; images.len()];
+    downscale_videos(&mut images, &previous, dry_run);
+    save_gallery(&mut Gallery::new(info, images), Path::new(MD_FILE), &read, dry_run);
+}
+
+fn html(dry_run: bool) {
+    save_html(dry_run);
+    if let Ok(gallery) = load_metadata(Path::new(MD_FILE)) {
+        in_albums(&album_paths(&gallery), |_| html(dry_run));
+    }
+}
+
+// The subdirectories with media, at any depth, which are the albums of the gallery.
+fn find_albums() -> Vec<String> {
+    let mut albums = Vec::new();
+    if let Ok(entries) = fs::read_dir(".") {
+        for entry in entries.flatten() {
+            // Links aren't followed, so we can't loop
+            if !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                continue;
+            }
+            match entry.file_name().into_string() {
+                Ok(name) if name.starts_with('.') => (),
+                Ok(name) => if has_media(&entry.path()) {
+                    albums.push(name);
+                },
+                Err(name) => println!("Skipping the album {} as the name is not UTF8", name.to_string_lossy())
+            }
+        }
+    }
+    albums.sort();
+
+    albums
+}
+
+fn has_media(dir: &Path) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return false
+    };
+    entries.flatten().any(|entry| {
+        let name = entry.file_name().to_string_lossy().to_string();
+        match entry.file_type() {
+            Ok(t) if t.is_file() => MediaKind::from_path(&name).is_some() && !is_generated(&name),
+            Ok(t) if t.is_dir() => !name.starts_with('.') && has_media(&entry.path()),
+            _ => false
         }
+    })
+}
+
+fn album_paths(gallery: &Gallery) -> Vec<String> {
+    gallery.gallery.albums.iter().map(|a| a.path.clone()).collect()
+}
+
+// Runs 'f' in each album directory, returning what it returns for each.
+fn in_albums<T>(albums: &[String], mut f: impl FnMut(&str) -> T) -> Vec<T> {
+    albums.iter().map(|album| {
+        if let Err(e) = env::set_current_dir(album) {
+            println!("Unable to use the album {}: {}", album, e);
+            exit(1);
+        }
+        println!("Album {}", env::current_dir().map(|d| d.display().to_string()).unwrap_or(album.clone()));
+        let result = f(album);
+        env::set_current_dir("..").expect("The gallery of an album is its parent directory");
+        result
+    }).collect()
+}
+
+// Albums are ordered like photos, by date unless sorting by file name or not at all.
+fn add_albums(info: &mut GalleryInfo, mut albums: Vec<Album>, sort: &SortArgs) {
+    if !sort.no_sort && !sort.filename_number && !sort.filename_date {
+        albums.sort_by_key(|a| a.start);
     }
+    info.albums = albums;
+    add_dates(info);
+}
+
+// Extends the dates of the gallery to cover those of its albums.
+fn add_dates(info: &mut GalleryInfo) {
+    info.start = info.start.into_iter().chain(info.albums.iter().filter_map(|a| a.start)).min();
+    info.end = info.end.into_iter().chain(info.albums.iter().filter_map(|a| a.end)).max();
+}
+
+// Shown in the albums, to link back to the gallery
+fn gallery_title(info: &GalleryInfo) -> String {
+    match info.title {
+        Some(ref t) => t.clone(),
+        None => env::current_dir().ok()
+                                  .and_then(|d| d.file_name().map(|n| n.to_string_lossy().to_string()))
+                                  .unwrap_or_default()
+    }
+}
 
-    let mut images = match read_exif(date_srt) {
+// Returns the media with, for each item, its index in the existing metadata if it's unchanged.
+fn read_sorted_media(sort: &SortArgs, incremental: bool, regenerate: bool, dry_run: bool) -> (Vec<Image>, Vec<Option<usize>>) {
+    let existing = if incremental && Path::new(MD_FILE).exists() {
+        match load_metadata(Path::new(MD_FILE)) {
+            Ok(g) => g.items,
+            Err(e) => {
+                println!("Unable to read {}, so processing everything: {}", MD_FILE, e);
+                Vec::new()
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    let mut media = match read_media(sort.filename_date, &existing, regenerate, dry_run) {
         Ok(i) => i,
         Err(e) => { 
             println!("Unable to parse images {}", e.to_string()); 
-            return; 
+            exit(1);
         }
     };
 
-    if !no_srt {
-        if num_srt {
-            images.sort_by_key(|i| get_digits(&i.path).parse::<u32>().unwrap());
+    // Keep the existing order, e.g. for items with the same time, and add anything new at the end.
+    // The sorts are stable so this carries through.
+    media.sort_by_key(|m| m.1.unwrap_or(usize::MAX));
+
+    if !sort.no_sort {
+        if sort.filename_number {
+            media.sort_by_key(|m| get_digits(&m.0.path).parse::<u32>().unwrap_or(0));
         } else {
-            images.sort_by_key(|i| i.time);
+            media.sort_by_key(|m| m.0.time);
         }
     }
 
-    // save metadata
-    let mut path = if metadata_only {
-        std::env::temp_dir()
+    if incremental {
+        let unchanged = media.iter().filter(|m| m.1.is_some()).count();
+        let paths: HashSet<&str> = media.iter().map(|m| m.0.path.as_str()).collect();
+        let removed = existing.iter().filter(|i| !paths.contains(i.path.as_str())).count();
+        println!("{} items are unchanged, {} are new or changed and {} were removed", unchanged, media.len() - unchanged, removed);
+    }
+
+    media.into_iter().unzip()
+}
+
+fn load_existing_metadata() -> Gallery {
+    match load_metadata(Path::new(MD_FILE)) {
+        Ok(g) => g,
+        Err(e) => {
+            println!("Unable to read {}, so run 'make-gallery metadata' first: {}", MD_FILE, e);
+            exit(1);
+        }
+    }
+}
+
+fn gallery_info(images: &Vec<Image>, parent: Option<&str>) -> GalleryInfo {
+    match read_gallery_info(images) {
+        Ok(i) => GalleryInfo { parent: parent.map(String::from), ..i },
+        Err(e) => {
+            println!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+// The captions in the metadata before it's rebuilt, so those edited while make-gallery runs can be
+// told apart when saving
+fn read_captions() -> HashMap<String, String> {
+    match load_metadata(Path::new(MD_FILE)) {
+        Ok(g) => g.items.into_iter().map(|i| (i.path, i.caption)).collect(),
+        Err(_) => HashMap::new()
+    }
+}
+
+fn save_gallery(gallery: &mut Gallery, path: &Path, read: &HashMap<String, String>, dry_run: bool) {
+    if dry_run {
+        println!("Would write metadata for {} items to {}", gallery.items.len(), path.display());
+        return;
+    }
+    // Anything else, e.g. from 'metadata --output', isn't the metadata nginx edits
+    let result = if path == Path::new(MD_FILE) {
+        save_metadata_keeping_captions(path, gallery, read)
     } else {
-        PathBuf::new()
+        save_metadata(path, gallery)
     };
-    path.push(MD_FILE);
-    let _ = fs::write(path, serde_json::to_string_pretty(&images).unwrap());
+    if let Err(e) = result {
+        println!("Unable to write {} with error {}", path.display(), e);
+        exit(1);
+    }
+}
 
-    if metadata_only {
+fn make_thumbnails(images: &Vec<Image>, previous: &[Option<usize>], options: &ResizeOptions, dry_run: bool) {
+    // The photos are all in albums
+    if images.is_empty() {
         return;
     }
+    if dry_run {
+        let changed = images.iter().zip(previous).filter(|(_, p)| p.is_none());
+        println!("Would make thumbnails for {} items and previews for {} videos", changed.clone().count(), changed.filter(|(i, _)| i.is_mp4()).count());
+        return;
+    }
+    make_preview(images, previous, options);
+}
 
-    make_preview(&images);
-    
-    downscale_videos(&mut images);
+// Reports everything that's missing rather than stopping at the first problem.
+fn validate() -> bool {
+    let gallery = match load_metadata(Path::new(MD_FILE)) {
+        Ok(g) => g,
+        Err(e) => {
+            println!("Unable to read {}: {}", MD_FILE, e);
+            return false;
+        }
+    };
 
+    let mut ok = true;
+    let mut problem = |msg: String| {
+        println!("{}", msg);
+        ok = false;
+    };
 
-    save_html();
-}
+    let images = &gallery.items;
+    if images.is_empty() && gallery.gallery.albums.is_empty() {
+        problem(String::from("The gallery has no photos or videos"));
+    }
+    if let Err(e) = read_gallery_info(images) {
+        problem(e);
+    }
+
+    let mut paths = HashSet::new();
+    for image in images {
+        if !paths.insert(image.path.as_str()) {
+            problem(format!("{} is in the metadata more than once", image.path));
+        }
+        if !Path::new(&image.path).is_file() {
+            problem(format!("{} is in the metadata but doesn't exist", image.path));
+        }
+        if image.kind().is_none() {
+            problem(format!("{} isn't a supported photo or video", image.path));
+        }
+        if image.is_mp4() && !Path::new(&format!("{}.preview.jpg", image.path)).is_file() {
+            problem(format!("{} has no preview, so run 'make-gallery thumbnails'", image.path));
+        }
+        if image.mp4_scaled && !Path::new(&format!("{}.scaled.mp4", image.path)).is_file() {
+            problem(format!("{} has no downscaled video, so run 'make-gallery videos'", image.path));
+        }
+    }
+
+    if !images.is_empty() && !Path::new("thumbnails.jpg").is_file() {
+        problem(String::from("thumbnails.jpg doesn't exist, so run 'make-gallery thumbnails'"));
+    }
+    for file in ["index.html", "edit_caption.js"] {
+        if !Path::new(file).is_file() {
+            problem(format!("{} doesn't exist, so run 'make-gallery html'", file));
+        }
+    }
+
+    // Not an error, but probably a mistake
+    if let Ok(entries) = fs::read_dir(".") {
+        for name in entries.flatten().filter_map(|e| e.file_name().into_string().ok()) {
+            if MediaKind::from_path(&name).is_some() && !is_generated(&name) && !paths.contains(name.as_str()) {
+                println!("{} isn't in the metadata, so run 'make-gallery metadata' to add it", name);
+            }
+        }
+    }
+
+    let albums = album_paths(&gallery);
+    for album in find_albums() {
+        if !albums.contains(&album) {
+            println!("The album {} isn't in the metadata, so run 'make-gallery metadata' to add it", album);
+        }
+    }
+    for album in &albums {
+        if !Path::new(album).join(MD_FILE).is_file() {
+            problem(format!("The album {} has no metadata, so run 'make-gallery metadata'", album));
+        }
+    }
+    let albums: Vec<String> = albums.into_iter().filter(|a| Path::new(a).join(MD_FILE).is_file()).collect();
+    if !in_albums(&albums, |_| validate()).into_iter().all(|a| a) {
+        ok = false;
+    }
 
-// Extract value from a JSON metadata line, e.g. `    "path": "foo.jpg",`
-fn extract_value(line: &str) -> String {
-    let value = line
-        .split_once(':')
-        .expect("Metadata not well formed")
-        .1
-        .trim()
-        .trim_end_matches(',');
+    if ok {
+        println!("{} has {} items and is ready to be served", env::current_dir().map(|d| d.display().to_string()).unwrap_or_default(), gallery.count());
+    }
+
+    ok
+}
 
-    serde_json::from_str(value).expect("Metadata value is not a valid JSON string")
+// Files written by make-gallery that look like media
+fn is_generated(path: &str) -> bool {
+    path.ends_with(".preview.jpg") || path.ends_with(".scaled.mp4") || path.starts_with("thumbnails")
 }
 
 fn load_captions() -> HashMap<String, String> {
@@ -107,24 +643,11 @@ fn load_captions() -> HashMap<String, String> {
     let file = match File::open("captions.txt") {
         Ok(f) => f,
         Err(_) => { 
-            // Maybe there's an old metadata file with captions?
-            // Read as text because it's simpler than dealing with
-            // metadata schema versioning.
-            let md = match File::open(MD_FILE) {
-                Ok(f) => f,
-                Err(_) => {
-                    return captions;
-                }
-            };
-
-            let mut path = String::new();
-            let rdr = BufReader::new(md);
-            for line in rdr.lines() {
-                let l = line.expect("Badly formed metadata line");
-                if l.starts_with("    \"path") {
-                    path = extract_value(&l);
-                } else if l.starts_with("    \"caption") {
-                    captions.insert(path.clone(), extract_value(&l));
+            // Maybe there's an old metadata file with captions? Older versions are migrated
+            // when loaded.
+            if let Ok(gallery) = load_metadata(Path::new(MD_FILE)) {
+                for image in gallery.items {
+                    captions.insert(image.path, image.caption);
                 }
             }
             return captions;
@@ -142,8 +665,9 @@ fn load_captions() -> HashMap<String, String> {
     captions
 }
 
-fn run(cmd: &str) -> std::io::Result<Output> {
-    match Command::new("bash").args(["-c", cmd]).output() {
+// Runs the program directly, not through a shell, so file names are passed as they are.
+fn run(program: &str, args: &[&str]) -> std::io::Result<Output> {
+    match Command::new(program).args(args).output() {
         Ok(o) => {
             if !o.status.success() {
                 let mut error = Vec::<u8>::with_capacity(o.stdout.len() + o.stderr.len());
@@ -161,16 +685,6 @@ fn get_empty_date() -> NaiveDateTime {
     return DateTime::from_timestamp(0, 0).unwrap().naive_utc();
 }
 
-fn assign_date(exiftool_line: &str, image: &mut Image) {
-    let date_time_str = &exiftool_line[exiftool_line.rfind(": ").unwrap() + 2 ..];
-    match NaiveDateTime::parse_from_str(date_time_str, "%Y:%m:%d %H:%M:%S") {
-        Ok(dt) => { image.time = dt; },
-        Err(e) => { 
-            println!("Unable to parse a date {} for {} with error: {}. Verify photo ordering to determine if it's correct.", date_time_str, image.path, e.to_string());
-        }
-    };
-}
-
 fn assign_date_from_filename(image: &mut Image) {
     let mut date = get_digits(&image.path);
     if date.len() != 14 {
@@ -205,115 +719,154 @@ fn get_digits(s: &String) -> String{
     return s.chars().filter(|c| c.is_digit(10)).collect();
 }
 
-fn read_exif(use_fn_date: bool) -> Result<Vec<Image>, String> {
+// Reads the media in the directory, reusing the existing metadata for anything unchanged, and
+// returns each item with its index in the existing metadata if it's reused. 'regenerate' is
+// whether the previews and downscaled videos of changed videos are about to be remade.
+fn read_media(use_fn_date: bool, existing: &Vec<Image>, regenerate: bool, dry_run: bool) -> Result<Vec<(Image, Option<usize>)>, String> {
     let captions = load_captions();
+    let existing_index: HashMap<&str, usize> = existing.iter().enumerate().map(|(n, i)| (i.path.as_str(), n)).collect();
 
-    // exiftool seems much more robust and complete than any alternatives, so we spawn
-    let cmd = "shopt -s nullglob &&
-                exiftool -m -d '%Y:%m:%d %H:%M:%S' -CreateDate -DateTimeOriginal -FileModifyDate -ImageWidth -ImageHeight -GPSPosition *.{jpg,JPG,mp4,MP4,mov,MOV,avi,AVI}";
-    
-    // Run it through bash to get path expansion rather than running exif directly
-    let output = match run(cmd) {
-        Ok(o) => o,
-        Err(e) => return Err(e.to_string())        
-    };
+    let mut paths = Vec::<String>::new();
+    for entry in fs::read_dir(".").map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        if !entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+            continue;
+        }
+        match entry.file_name().into_string() {
+            Ok(p) => paths.push(p),
+            Err(p) => println!("Skipping {} as the name is not UTF8", p.to_string_lossy())
+        }
+    }
+    paths.sort();
+
+    let mut media = Vec::<(Image, Option<usize>)>::new();
+    let mut generated = Vec::<String>::new();
+    for path in paths {
+        let kind = match MediaKind::from_path(&path) {
+            Some(k) => k,
+            None => continue
+        };
+        if is_generated(&path) {
+            generated.push(path);
+            continue;
+        }
 
-    let mut images = Vec::<Image>::new();
-    let path_delimiter = "======== ";
-    let mut wait_for_next = false;
-
-    for line in String::from_utf8(output.stdout).unwrap().lines() {
-        if line.starts_with(path_delimiter) {
-            wait_for_next = false;
-            let path = line[path_delimiter.len()..].to_string();
-            if path.ends_with(".preview.jpg") || path.ends_with(".scaled.mp4") || path.starts_with("thumbnails") {
-                let _ = fs::remove_file(&path);
-                wait_for_next = true;
+        let (size, mtime) = file_stamp(Path::new(&path));
+        if let Some(&n) = existing_index.get(path.as_str()) {
+            if existing[n].size == size && existing[n].mtime == mtime && size != 0 {
+                media.push((existing[n].clone(), Some(n)));
                 continue;
             }
-            let caption = match captions.get(&path) {
-                Some(c) => c,
-                None => ""
-            };
-            images.push(Image{  path: path, 
+        }
+
+        let caption = match captions.get(&path) {
+            Some(c) => c,
+            None => ""
+        };
+        let mut image = Image{  path: path, 
                                 caption: caption.to_string(),
                                 time: get_empty_date(), 
                                 height: 0, 
                                 width: 0,
                                 mp4_scaled: false,
-                                location: None });
-            continue;
-        }
-        if wait_for_next {
-            continue;
-        }
-
-        let index = images.len() - 1;
+                                location: None,
+                                kind: Some(kind),
+                                gps: None,
+                                size: size,
+                                mtime: mtime };
 
         if use_fn_date {
-            if images[index].time == get_empty_date() {
-                assign_date_from_filename(&mut images[index]);
-            }            
+            assign_date_from_filename(&mut image);
         }
 
-        if line.starts_with("Create Date") {
-            if images[index].time != get_empty_date() {
-                continue;
-            }            
-            assign_date(&line, &mut images[index]);
-            continue;
-        }
-        if line.starts_with("Date/Time Original") {
-            if images[index].time != get_empty_date() {
-                continue;
-            }            
-            assign_date(&line, &mut images[index]);
-            continue;
-        }
-        if line.starts_with("File Modification Date") {
-            if images[index].time != get_empty_date() {
-                continue;
-            }            
-            assign_date(&line, &mut images[index]);
-            continue;
+        match read_metadata(Path::new(&image.path), kind) {
+            Ok(md) => {
+                if image.time == get_empty_date() {
+                    if let Some(t) = md.time {
+                        image.time = t;
+                    }
+                }
+                image.width = to_u16(md.width);
+                image.height = to_u16(md.height);
+                image.location = md.gps.map(|g| g.to_location());
+                image.gps = md.gps;
+            },
+            Err(e) => {
+                println!("Unable to read metadata of {} with error: {}", image.path, e);
+            }
         }
-        if line.starts_with("Image Width") {
-            images[index].width = u16::from_str(&line[line.rfind(": ").unwrap() + 2 ..]).unwrap();
-            continue;
+
+        if image.time == get_empty_date() {
+            match modified_time(Path::new(&image.path)) {
+                Some(t) => image.time = t,
+                None => println!("Unable to find a date for {}. Verify photo ordering to determine if it's correct.", image.path)
+            }
         }
-        if line.starts_with("Image Height") {
-            images[index].height = u16::from_str(&line[line.rfind(": ").unwrap() + 2 ..]).unwrap();
+
+        media.push((image, None));
+    }
+
+    // Previews and downscaled videos of changed videos are stale, so they're removed if they're
+    // about to be remade, as are those whose video has gone. The thumbnails files are kept to see
+    // whether they can be reused.
+    let unchanged: HashSet<&str> = media.iter().filter(|m| m.1.is_some()).map(|m| m.0.path.as_str()).collect();
+    let present: HashSet<&str> = media.iter().map(|m| m.0.path.as_str()).collect();
+    for path in generated {
+        let source = path.strip_suffix(".preview.jpg").or_else(|| path.strip_suffix(".scaled.mp4"));
+        let keep = match source {
+            Some(s) => unchanged.contains(s) || (!regenerate && present.contains(s)),
+            None => true
+        };
+        if keep {
             continue;
         }
-        if line.starts_with("GPS Position") {
-            images[index].location = Some(line[line.rfind(": ").unwrap() + 2 ..].to_string());
-            continue;
+        if dry_run {
+            println!("Would remove {}", path);
+        } else {
+            let _ = fs::remove_file(&path);
         }
     }
 
-    Ok(images)
+    Ok(media)
 }
 
-fn downscale_videos(images: &mut Vec<Image>) {
-    for img in images {
-        if img.is_mp4() && needs_scaling(img) {
-            img.mp4_scaled = true;
-            println!("Downscaling {}", img.path);
-            let cmd = format!("ffmpeg -y -i {} -vf scale=1920:-2 -c:a copy -c:v libx264 -f mp4 {}.scaled.mp4", img.path, img.path);
-            match run(cmd.as_str()) {
-                Ok(_) => (),
-                Err(e) => {
-                    println!("Failed to scale {} with error {}", img.path, e);
-                }
+fn to_u16(n: u32) -> u16 {
+    u16::try_from(n).unwrap_or(u16::MAX)
+}
+
+fn downscale_videos(images: &mut Vec<Image>, previous: &[Option<usize>], dry_run: bool) {
+    let progress = Progress::new(images.iter().filter(|i| i.is_mp4()).count());
+
+    images.par_iter_mut().zip(previous).filter(|(img, _)| img.is_mp4()).for_each(|(img, prev)| {
+        // Unchanged videos were already probed, and scaled if need be
+        if prev.is_some() && (!img.mp4_scaled || Path::new(&format!("{}.scaled.mp4", img.path)).exists()) {
+            progress.report(&format!("{} is unchanged", img.path));
+            return;
+        }
+        if !needs_scaling(img) {
+            progress.report(&format!("{} doesn't need downscaling", img.path));
+            return;
+        }
+
+        img.mp4_scaled = true;
+        if dry_run {
+            progress.report(&format!("Would downscale {}", img.path));
+            return;
+        }
+        let input = file_arg(&img.path);
+        let output = file_arg(&format!("{}.scaled.mp4", img.path));
+        match run("ffmpeg", &["-y", "-i", &input, "-vf", "scale=1920:-2", "-c:a", "copy", "-c:v", "libx264", "-f", "mp4", &output]) {
+            Ok(_) => progress.report(&format!("Downscaled {}", img.path)),
+            Err(e) => {
+                progress.report(&format!("Failed to scale {} with error {}", img.path, e));
             }
         }
-    }
+    });
 }
 
 // Can the video format not be rendered by browsers, or is it too large for normal screens?
 fn needs_scaling(image: &Image) -> bool {
-    let cmd = format!("ffprobe -v error -select_streams v:0 -show_entries stream=codec_name {}", image.path);
-    let output = match run(cmd.as_str()) {
+    let output = match run("ffprobe", &["-v", "error", "-select_streams", "v:0", "-show_entries", "stream=codec_name", &file_arg(&image.path)]) {
         Ok(o) => o,
         Err(e) => {
             println!("Error getting encoding of {}: {}", image.path, e);
@@ -329,7 +882,12 @@ fn needs_scaling(image: &Image) -> bool {
     image.width > 1920  // we scale to ~ 1920x1080, which is somewhat arbitrary
 }
 
-fn save_html() {
+fn save_html(dry_run: bool) {
+    if dry_run {
+        println!("Would write index.html and edit_caption.js");
+        return;
+    }
+
     let index = include_str!("../../../html/index.html");
     let _ = fs::write("index.html", index);
 
@@ -0,0 +1,219 @@
+use std::fs;
+use std::fs::File;
+
+use std::io::{ BufReader, Read };
+
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use chrono::{ DateTime, Local, NaiveDate, NaiveDateTime, Timelike };
+
+use exif::{ Exif, In, Reader, Tag, Value };
+
+use rust_gallery::GpsPosition;
+use rust_gallery::MediaKind;
+use rust_gallery::is_transposed;
+
+use crate::video::read_video;
+
+// What we need from a photo or video for the gallery. The size is as displayed, i.e. after rotation.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub time: Option<NaiveDateTime>,
+    pub width: u32,
+    pub height: u32,
+    pub gps: Option<GpsPosition>
+}
+
+pub fn read_metadata(path: &Path, kind: MediaKind) -> Result<Metadata, String> {
+    match kind {
+        MediaKind::Video => read_video(path).map_err(|e| e.to_string()),
+        _ => read_photo(path)
+    }
+}
+
+// The modified time of the file in local time, for when there's nothing better.
+pub fn modified_time(path: &Path) -> Option<NaiveDateTime> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    DateTime::<Local>::from(modified).naive_local().with_nanosecond(0)
+}
+
+// The size and modified time (seconds since the epoch) of the file, to tell if it has changed.
+pub fn file_stamp(path: &Path) -> (u64, u64) {
+    match fs::metadata(path) {
+        Ok(md) => (md.len(), md.modified().ok()
+                               .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                               .map(|d| d.as_secs())
+                               .unwrap_or(0)),
+        Err(_) => (0, 0)
+    }
+}
+
+fn read_photo(path: &Path) -> Result<Metadata, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+
+    // Not having EXIF is fine, e.g. for screenshots
+    let exif = Reader::new().read_from_container(&mut BufReader::new(file)).ok();
+
+    let mut md = Metadata::default();
+    if let Some(ref exif) = exif {
+        // Same precedence as we had with exiftool: CreateDate then DateTimeOriginal
+        md.time = get_date(exif, Tag::DateTimeDigitized).or_else(|| get_date(exif, Tag::DateTimeOriginal));
+        md.gps = get_gps(exif);
+    }
+
+    // Only read the XMP if EXIF didn't have what we need
+    if md.time.is_none() || md.gps.is_none() {
+        if let Some(xmp) = read_xmp(path) {
+            md.time = md.time.or_else(|| get_xmp_date(&xmp));
+            md.gps = md.gps.or_else(|| get_xmp_gps(&xmp));
+        }
+    }
+
+    // The image header is more reliable than EXIF, which editors don't always update.
+    // The image crate can't read HEIC, so fall back to EXIF for that.
+    let (width, height) = match image::image_dimensions(path) {
+        Ok(d) => d,
+        Err(_) => match exif {
+            Some(ref exif) => (get_uint(exif, Tag::PixelXDimension).or_else(|| get_uint(exif, Tag::ImageWidth)).unwrap_or(0),
+                               get_uint(exif, Tag::PixelYDimension).or_else(|| get_uint(exif, Tag::ImageLength)).unwrap_or(0)),
+            None => return Err(String::from("Unable to determine the image size"))
+        }
+    };
+
+    let orientation = exif.as_ref().and_then(|e| get_uint(e, Tag::Orientation)).unwrap_or(1);
+    if is_transposed(orientation) {
+        md.width = height;
+        md.height = width;
+    } else {
+        md.width = width;
+        md.height = height;
+    }
+
+    Ok(md)
+}
+
+fn get_uint(exif: &Exif, tag: Tag) -> Option<u32> {
+    exif.get_field(tag, In::PRIMARY).and_then(|f| f.value.get_uint(0))
+}
+
+fn get_ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(ref v) => v.first().map(|s| String::from_utf8_lossy(s).trim().to_string()),
+        _ => None
+    }
+}
+
+fn get_date(exif: &Exif, tag: Tag) -> Option<NaiveDateTime> {
+    let date_time_str = get_ascii(exif, tag)?;
+    match NaiveDateTime::parse_from_str(&date_time_str, "%Y:%m:%d %H:%M:%S") {
+        Ok(dt) => Some(dt),
+        Err(e) => {
+            // Cameras without a clock set write all zeros, or blanks
+            println!("Unable to parse a date {} with error: {}. Verify photo ordering to determine if it's correct.", date_time_str, e);
+            None
+        }
+    }
+}
+
+fn get_gps(exif: &Exif) -> Option<GpsPosition> {
+    let latitude = get_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S")?;
+    let longitude = get_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W")?;
+
+    Some(GpsPosition { latitude, longitude })
+}
+
+// Coordinates are stored as degrees, minutes and seconds with a separate reference for the hemisphere
+fn get_coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative: &str) -> Option<f64> {
+    let dms = match exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(ref v) if v.len() == 3 => [v[0].to_f64(), v[1].to_f64(), v[2].to_f64()],
+        _ => return None
+    };
+    if dms.iter().any(|v| !v.is_finite()) {
+        return None;
+    }
+
+    let degrees = dms[0] + dms[1] / 60.0 + dms[2] / 3600.0;
+    match get_ascii(exif, ref_tag) {
+        Some(r) if r == negative => Some(-degrees),
+        _ => Some(degrees)
+    }
+}
+
+// How much of a file is searched for XMP. It's near the start of photos, and videos can be large.
+const XMP_MAX_READ: u64 = 256 * 1024;
+
+// Returns the XMP packet, e.g. for files edited by Lightroom or exported without EXIF.
+fn read_xmp(path: &Path) -> Option<String> {
+    let mut bytes = Vec::new();
+    File::open(path).ok()?.take(XMP_MAX_READ).read_to_end(&mut bytes).ok()?;
+    let start = find(&bytes, b"<x:xmpmeta")?;
+    let end = start + find(&bytes[start..], b"</x:xmpmeta>")?;
+
+    String::from_utf8(bytes[start..end].to_vec()).ok()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// XMP properties may be written as attributes, name="value", or as elements, <name>value</name>.
+fn get_xmp_property(xmp: &str, name: &str) -> Option<String> {
+    let attribute = format!("{}=\"", name);
+    if let Some(start) = xmp.find(&attribute).map(|i| i + attribute.len()) {
+        let end = start + xmp[start..].find('"')?;
+        return Some(xmp[start..end].trim().to_string());
+    }
+
+    let element = format!("<{}>", name);
+    let start = xmp.find(&element)? + element.len();
+    let end = start + xmp[start..].find('<')?;
+    Some(xmp[start..end].trim().to_string())
+}
+
+fn get_xmp_date(xmp: &str) -> Option<NaiveDateTime> {
+    ["xmp:CreateDate", "exif:DateTimeOriginal", "photoshop:DateCreated"].iter()
+        .filter_map(|name| get_xmp_property(xmp, name))
+        .find_map(|d| parse_xmp_date(&d))
+}
+
+// ISO 8601, possibly without a time and with fractional seconds or a time zone. We use the local
+// time as written, as we do for EXIF.
+fn parse_xmp_date(date: &str) -> Option<NaiveDateTime> {
+    if let Some(dt) = date.get(..19).and_then(|d| NaiveDateTime::parse_from_str(d, "%Y-%m-%dT%H:%M:%S").ok()) {
+        return Some(dt);
+    }
+    if let Some(dt) = date.get(..16).and_then(|d| NaiveDateTime::parse_from_str(d, "%Y-%m-%dT%H:%M").ok()) {
+        return Some(dt);
+    }
+    date.get(..10)
+        .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+}
+
+fn get_xmp_gps(xmp: &str) -> Option<GpsPosition> {
+    let latitude = parse_xmp_coordinate(&get_xmp_property(xmp, "exif:GPSLatitude")?)?;
+    let longitude = parse_xmp_coordinate(&get_xmp_property(xmp, "exif:GPSLongitude")?)?;
+
+    Some(GpsPosition { latitude, longitude })
+}
+
+// Either 'DDD,MM,SSk' or 'DDD,MM.mmk', where k is the hemisphere, e.g. '37,46.494N'
+fn parse_xmp_coordinate(coordinate: &str) -> Option<f64> {
+    let hemisphere = coordinate.chars().last()?;
+    let mut parts = coordinate[..coordinate.len() - hemisphere.len_utf8()].split(',');
+
+    let degrees = parts.next()?.parse::<f64>().ok()?;
+    let minutes = parts.next()?.parse::<f64>().ok()?;
+    let seconds = match parts.next() {
+        Some(s) => s.parse::<f64>().ok()?,
+        None => 0.0
+    };
+
+    let value = degrees + minutes / 60.0 + seconds / 3600.0;
+    match hemisphere {
+        'N' | 'E' => Some(value),
+        'S' | 'W' => Some(-value),
+        _ => None
+    }
+}
@@ -0,0 +1,215 @@
+use std::fs::File;
+
+use std::io::{ prelude::*, BufReader, Error, ErrorKind, SeekFrom };
+
+use std::path::Path;
+
+use chrono::{ DateTime, NaiveDateTime };
+
+use rust_gallery::GpsPosition;
+
+use crate::metadata::Metadata;
+
+// Seconds from the QuickTime epoch, 1904-01-01, to the Unix epoch
+const QUICKTIME_EPOCH_OFFSET: i64 = 2082844800;
+
+// The location as written by Apple devices in the moov/meta atom
+const APPLE_LOCATION_KEY: &[u8] = b"com.apple.quicktime.location.ISO6709";
+
+// Reads the creation time, size and location of an MP4/MOV (QuickTime atoms) or AVI (RIFF chunks).
+pub fn read_video(path: &Path) -> std::io::Result<Metadata> {
+    let mut file = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 12];
+    file.read_exact(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    if &magic[0..4] == b"RIFF" && &magic[8..12] == b"AVI " {
+        read_avi(&mut file)
+    } else {
+        read_quicktime(&mut file)
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn read_quicktime(file: &mut BufReader<File>) -> std::io::Result<Metadata> {
+    // The media data can be gigabytes, so skip over top level atoms until we find moov, which is small.
+    loop {
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        let mut size = u32::from_be_bytes(header[0..4].try_into().unwrap()) as u64;
+        let mut header_len = 8;
+        if size == 1 {
+            let mut large_size = [0u8; 8];
+            file.read_exact(&mut large_size)?;
+            size = u64::from_be_bytes(large_size);
+            header_len = 16;
+        }
+
+        if &header[4..8] == b"moov" {
+            if size == 0 {
+                // Runs to the end of the file
+                let mut body = Vec::new();
+                file.read_to_end(&mut body)?;
+                return Ok(parse_moov(&body));
+            }
+            let mut body = vec![0u8; size.checked_sub(header_len).ok_or_else(|| invalid("Bad moov atom size"))? as usize];
+            file.read_exact(&mut body)?;
+            return Ok(parse_moov(&body));
+        }
+
+        if size == 0 {
+            return Err(invalid("No moov atom"));
+        }
+        let skip = size.checked_sub(header_len).ok_or_else(|| invalid("Bad atom size"))?;
+        file.seek(SeekFrom::Current(skip as i64))?;
+    }
+}
+
+// Splits a buffer into (type, body) atoms, stopping at anything malformed.
+fn atoms(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut atoms = Vec::new();
+    while data.len() >= 8 {
+        let mut size = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+        let mut header_len = 8;
+        if size == 1 {
+            if data.len() < 16 {
+                break;
+            }
+            size = u64::from_be_bytes(data[8..16].try_into().unwrap()) as usize;
+            header_len = 16;
+        } else if size == 0 {
+            size = data.len();
+        }
+        if size < header_len || size > data.len() {
+            break;
+        }
+
+        atoms.push((&data[4..8], &data[header_len..size]));
+        data = &data[size..];
+    }
+
+    atoms
+}
+
+fn parse_moov(moov: &[u8]) -> Metadata {
+    let mut md = Metadata::default();
+
+    for (kind, body) in atoms(moov) {
+        match kind {
+            b"mvhd" => md.time = parse_mvhd(body),
+            b"trak" => {
+                // The first track with a size is the video; audio tracks have none
+                if md.width == 0 {
+                    if let Some((w, h)) = atoms(body).into_iter().find(|a| a.0 == b"tkhd").and_then(|a| parse_tkhd(a.1)) {
+                        md.width = w;
+                        md.height = h;
+                    }
+                }
+            },
+            b"udta" => {
+                if let Some(xyz) = atoms(body).into_iter().find(|a| a.0 == b"\xa9xyz") {
+                    // 16 bit string length and language, then the string
+                    md.gps = md.gps.or_else(|| xyz.1.get(4..).and_then(parse_iso6709));
+                }
+            },
+            b"meta" => md.gps = md.gps.or_else(|| parse_apple_location(body)),
+            _ => ()
+        }
+    }
+
+    md
+}
+
+fn parse_mvhd(mvhd: &[u8]) -> Option<NaiveDateTime> {
+    let created = match mvhd.first()? {
+        0 => u32::from_be_bytes(mvhd.get(4..8)?.try_into().unwrap()) as i64,
+        1 => u64::from_be_bytes(mvhd.get(4..12)?.try_into().unwrap()) as i64,
+        _ => return None
+    };
+
+    // Zero means it wasn't set
+    if created <= QUICKTIME_EPOCH_OFFSET {
+        return None;
+    }
+
+    // Stored in UTC, which is what exiftool reported too
+    DateTime::from_timestamp(created - QUICKTIME_EPOCH_OFFSET, 0).map(|d| d.naive_utc())
+}
+
+// The width and height are the last 8 bytes, in 16.16 fixed point.
+fn parse_tkhd(tkhd: &[u8]) -> Option<(u32, u32)> {
+    if tkhd.len() < 8 {
+        return None;
+    }
+    let dims = &tkhd[tkhd.len() - 8..];
+    let width = u32::from_be_bytes(dims[0..4].try_into().unwrap()) >> 16;
+    let height = u32::from_be_bytes(dims[4..8].try_into().unwrap()) >> 16;
+
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    Some((width, height))
+}
+
+// The meta atom has a list of keys, and an item list indexed by key number from 1.
+fn parse_apple_location(meta: &[u8]) -> Option<GpsPosition> {
+    // In MP4 files (but not QuickTime) meta has a version and flags before its children
+    let children = match atoms(meta).first() {
+        Some(a) if a.0 == b"hdlr" => atoms(meta),
+        _ => atoms(meta.get(4..)?)
+    };
+
+    let keys = children.iter().find(|a| a.0 == b"keys")?.1;
+    let ilst = children.iter().find(|a| a.0 == b"ilst")?.1;
+
+    // Skip the version, flags and count; the keys are atoms whose type is the namespace
+    let index = atoms(keys.get(8..)?).iter().position(|k| k.1 == APPLE_LOCATION_KEY)? as u32 + 1;
+
+    let item = atoms(ilst).into_iter().find(|a| a.0 == index.to_be_bytes())?;
+    let data = atoms(item.1).into_iter().find(|a| a.0 == b"data")?;
+
+    // Skip the type and locale
+    parse_iso6709(data.1.get(8..)?)
+}
+
+// E.g. '+37.7749-122.4194+012.345/', latitude and longitude in decimal degrees then an optional altitude
+fn parse_iso6709(value: &[u8]) -> Option<GpsPosition> {
+    let s = std::str::from_utf8(value).ok()?.trim_end_matches(|c: char| c == '\0' || c == '/');
+
+    let mut starts = s.char_indices().filter(|(_, c)| *c == '+' || *c == '-').map(|(i, _)| i);
+    if starts.next()? != 0 {
+        return None;
+    }
+    let lon_start = starts.next()?;
+    let lon_end = starts.next().unwrap_or(s.len());
+
+    let latitude = s[..lon_start].parse::<f64>().ok()?;
+    let longitude = s[lon_start..lon_end].parse::<f64>().ok()?;
+    if latitude.abs() > 90.0 || longitude.abs() > 180.0 {
+        return None;
+    }
+
+    Some(GpsPosition { latitude, longitude })
+}
+
+// AVI has the size in the main header, but no standard creation time or location.
+fn read_avi(file: &mut BufReader<File>) -> std::io::Result<Metadata> {
+    // The main header is near the start of the file
+    let mut header = Vec::new();
+    file.take(64 * 1024).read_to_end(&mut header)?;
+
+    let start = header.windows(4).position(|w| w == b"avih").ok_or_else(|| invalid("No AVI main header"))? + 8;
+    let avih = header.get(start..start + 40).ok_or_else(|| invalid("Truncated AVI main header"))?;
+
+    Ok(Metadata {
+        time: None,
+        width: u32::from_le_bytes(avih[32..36].try_into().unwrap()),
+        height: u32::from_le_bytes(avih[36..40].try_into().unwrap()),
+        gps: None
+    })
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::path::{ Path, PathBuf };
+use std::sync::Mutex;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use once_cell::sync::Lazy;
+
+use sha2::{ Digest, Sha256 };
+
+use uuid::Uuid;
+
+use crate::photos::ResizeOptions;
+
+// Approximate bytes used by each cache directory in this worker. Other workers write to the
+// same directory, so this is corrected by a rescan whenever eviction runs.
+static CACHE_SIZES: Lazy<Mutex<HashMap<PathBuf, u64>>> = Lazy::new(|| Mutex::new(HashMap::<PathBuf, u64>::new()));
+
+pub const DEFAULT_MAX_SIZE: u64 = 1 << 30;  // 1G
+
+// Evict down to this fraction of the max size so we don't evict on every write.
+const EVICTION_TARGET: f64 = 0.9;
+
+// Identifies a resized image. The original's name, size and mtime are part of the key, rather than
+// its id, so that replacing an original or renumbering the gallery doesn't serve a stale rendition.
+#[derive(Debug, Clone)]
+pub struct Rendition {
+    pub source: String,     // the original's file name
+    pub width: u32,
+    pub height: u32,
+    pub options: ResizeOptions,
+    pub size: u64,
+    pub mtime: u64
+}
+
+impl Rendition {
+    // Unique to the rendition, so also usable as an ETag.
+    pub fn tag(&self) -> String {
+        format!("{}x{}-{}-{}", self.width, self.height, self.options.tag(), self.source_key())
+    }
+
+    // Names can be long and have any characters, so they're hashed
+    fn source_key(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.source.as_bytes());
+        hasher.update(format!("\0{}\0{}", self.size, self.mtime).as_bytes());
+        hex::encode(&hasher.finalize()[..12])
+    }
+
+    fn file_name(&self) -> String {
+        format!("{}.{}", self.tag(), self.options.format.extension())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RenditionCache {
+    pub dir: PathBuf,
+    pub max_size: u64
+}
+
+// The size of the file and the seconds since the epoch that it was last modified, or 0s if it
+// can't be read.
+pub fn source_stat(path: &Path) -> (u64, u64) {
+    match fs::metadata(path) {
+        Ok(m) => (m.len(), m.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()).unwrap_or(0)),
+        Err(_) => (0, 0)
+    }
+}
+
+impl RenditionCache {
+    fn path(&self, gallery_path: &str, rendition: &Rendition) -> PathBuf {
+        let mut path = self.dir.clone();
+        path.push(gallery_path.trim_start_matches('/'));
+        path.push(rendition.file_name());
+
+        path
+    }
+
+    pub fn get(&self, gallery_path: &str, rendition: &Rendition) -> Option<Vec<u8>> {
+        let path = self.path(gallery_path, rendition);
+        let bytes = fs::read(&path).ok()?;
+
+        // The modified time doubles as the last access time for LRU eviction.
+        if let Ok(f) = File::options().write(true).open(&path) {
+            let _ = f.set_modified(SystemTime::now());
+        }
+
+        Some(bytes)
+    }
+
+    pub fn put(&self, gallery_path: &str, rendition: &Rendition, bytes: &[u8]) {
+        let path = self.path(gallery_path, rendition);
+        let dir = path.parent().expect("Cache path has a directory");
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("Unable to create cache directory {} with error {}", dir.display(), e);
+            return;
+        }
+
+        // Write to a temporary file and rename so other workers never see a partial file.
+        let tmp = dir.join(format!(".{}.tmp", Uuid::new_v4()));
+        if let Err(e) = fs::write(&tmp, bytes) {
+            eprintln!("Unable to write cache file {} with error {}", tmp.display(), e);
+            let _ = fs::remove_file(&tmp);
+            return;
+        }
+        if let Err(e) = fs::rename(&tmp, &path) {
+            eprintln!("Unable to write cache file {} with error {}", path.display(), e);
+            let _ = fs::remove_file(&tmp);
+            return;
+        }
+
+        let mut sizes = CACHE_SIZES.lock().unwrap();
+        let size = sizes.entry(self.dir.clone()).or_insert_with(|| self.scan().iter().map(|f| f.1).sum());
+        *size += bytes.len() as u64;
+        if *size > self.max_size {
+            *size = self.evict();
+        }
+    }
+
+    // Returns every cached file with its size and last access time.
+    fn scan(&self) -> Vec<(PathBuf, u64, SystemTime)> {
+        let mut files = Vec::new();
+        let mut dirs = vec![self.dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(e) => e,
+                Err(_) => continue
+            };
+            for entry in entries.flatten() {
+                let md = match entry.metadata() {
+                    Ok(m) => m,
+                    Err(_) => continue
+                };
+                if md.is_dir() {
+                    dirs.push(entry.path());
+                } else {
+                    files.push((entry.path(), md.len(), md.modified().unwrap_or(UNIX_EPOCH)));
+                }
+            }
+        }
+
+        files
+    }
+
+    // Removes the least recently used files; returns the size of what's left.
+    fn evict(&self) -> u64 {
+        let mut files = self.scan();
+        files.sort_by_key(|f| f.2);
+
+        let mut size: u64 = files.iter().map(|f| f.1).sum();
+        let target = (self.max_size as f64 * EVICTION_TARGET) as u64;
+        for (path, len, _) in files {
+            if size <= target {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                size -= len;
+            }
+        }
+
+        size
+    }
+}
+
+// Parses an nginx style size, e.g. '512m' or '2g'.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let (digits, multiplier) = match s.chars().last()? {
+        'k' | 'K' => (&s[..s.len() - 1], 1u64 << 10),
+        'm' | 'M' => (&s[..s.len() - 1], 1u64 << 20),
+        'g' | 'G' => (&s[..s.len() - 1], 1u64 << 30),
+        _ => (s, 1)
+    };
+
+    digits.parse::<u64>().ok().and_then(|d| d.checked_mul(multiplier))
+}
@@ -0,0 +1,90 @@
+use serde::Deserialize;
+
+// In characters, unless rust_gallery_caption_max_length says otherwise
+pub const DEFAULT_MAX_LENGTH: usize = 2000;
+
+#[derive(Debug, PartialEq)]
+pub enum CaptionError {
+    Invalid(&'static str),
+    TooLong(usize)
+}
+
+#[derive(Deserialize)]
+struct CaptionBody {
+    caption: String
+}
+
+// The caption posted by edit_caption.js, as JSON, e.g. '{"caption": "..."}', or a form,
+// 'caption=...'.
+pub fn parse_caption(content_type: &str, body: &[u8], max_length: usize) -> Result<String, CaptionError> {
+    let body = std::str::from_utf8(body).map_err(|_| CaptionError::Invalid("The caption isn't UTF-8"))?;
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+
+    let caption = if mime.eq_ignore_ascii_case("application/json") {
+        serde_json::from_str::<CaptionBody>(body).map_err(|_| CaptionError::Invalid("The caption isn't valid JSON"))?.caption
+    } else if mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        let value = body.split('&')
+                        .filter_map(|p| p.split_once('='))
+                        .find(|(name, _)| *name == "caption")
+                        .ok_or(CaptionError::Invalid("There's no caption"))?.1;
+        let bytes = urlencoding::decode_binary(value.replace('+', " ").as_bytes()).into_owned();
+        String::from_utf8(bytes).map_err(|_| CaptionError::Invalid("The caption isn't UTF-8"))?
+    } else {
+        return Err(CaptionError::Invalid("The caption must be JSON or a form"));
+    };
+
+    if caption.chars().count() > max_length {
+        return Err(CaptionError::TooLong(max_length));
+    }
+
+    Ok(caption)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = "application/json";
+    const FORM: &str = "application/x-www-form-urlencoded";
+
+    #[test]
+    fn parses_json() {
+        assert_eq!(parse_caption(JSON, br#"{"caption": "At the top"}"#, 100), Ok("At the top".to_string()));
+        assert_eq!(parse_caption("Application/JSON; charset=utf-8", br#"{"caption": "a \"quote\""}"#, 100), Ok("a \"quote\"".to_string()));
+        assert_eq!(parse_caption(JSON, br#"{"caption": ""}"#, 100), Ok(String::new()));
+        assert_eq!(parse_caption(JSON, br#"{"caption": 12}"#, 100), Err(CaptionError::Invalid("The caption isn't valid JSON")));
+        assert_eq!(parse_caption(JSON, br#"{"title": "x"}"#, 100), Err(CaptionError::Invalid("The caption isn't valid JSON")));
+        assert_eq!(parse_caption(JSON, b"caption=x", 100), Err(CaptionError::Invalid("The caption isn't valid JSON")));
+    }
+
+    #[test]
+    fn parses_forms() {
+        assert_eq!(parse_caption(FORM, b"caption=Sunset", 100), Ok("Sunset".to_string()));
+        assert_eq!(parse_caption(FORM, b"id=3&caption=Sunset+over%20the+bay&x=1", 100), Ok("Sunset over the bay".to_string()));
+        assert_eq!(parse_caption(FORM, b"caption=1%2B1%3D2%26more", 100), Ok("1+1=2&more".to_string()));
+        assert_eq!(parse_caption(FORM, b"caption=", 100), Ok(String::new()));
+        assert_eq!(parse_caption(FORM, b"title=Sunset", 100), Err(CaptionError::Invalid("There's no caption")));
+    }
+
+    #[test]
+    fn checks_utf8() {
+        assert_eq!(parse_caption(JSON, "{\"caption\": \"Þingvellir\"}".as_bytes(), 100), Ok("Þingvellir".to_string()));
+        assert_eq!(parse_caption(FORM, b"caption=%C3%9Eingvellir", 100), Ok("Þingvellir".to_string()));
+        assert_eq!(parse_caption(FORM, b"caption=%FF", 100), Err(CaptionError::Invalid("The caption isn't UTF-8")));
+        assert_eq!(parse_caption(JSON, b"{\"caption\": \"\xff\"}", 100), Err(CaptionError::Invalid("The caption isn't UTF-8")));
+    }
+
+    #[test]
+    fn needs_json_or_a_form() {
+        assert_eq!(parse_caption("text/plain", b"caption=x", 100), Err(CaptionError::Invalid("The caption must be JSON or a form")));
+        assert_eq!(parse_caption("", b"caption=x", 100), Err(CaptionError::Invalid("The caption must be JSON or a form")));
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        assert_eq!(parse_caption(FORM, b"caption=abcde", 5), Ok("abcde".to_string()));
+        assert_eq!(parse_caption(FORM, b"caption=abcdef", 5), Err(CaptionError::TooLong(5)));
+        assert_eq!(parse_caption(JSON, "{\"caption\": \"ÞÞÞÞÞ\"}".as_bytes(), 5), Ok("ÞÞÞÞÞ".to_string()));
+        assert_eq!(parse_caption(JSON, "{\"caption\": \"ÞÞÞÞÞÞ\"}".as_bytes(), 5), Err(CaptionError::TooLong(5)));
+    }
+}
@@ -0,0 +1,80 @@
+use ngx::http;
+
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+
+use crate::ip::{ check_rules, get_client, AccessRule, IpRange, RealIpHeader };
+
+// The address of the other end of the connection, which may be a proxy.
+pub fn get_peer_ip(request: &http::Request) -> Option<IpAddr> {
+    unsafe {
+        let sa = (*request.connection()).sockaddr;
+        if sa.is_null() {
+            return None;
+        }
+
+        match (*sa).sa_family as i32 {
+            libc::AF_INET => {
+                let sin = sa as *const libc::sockaddr_in;
+                Some(IpAddr::V4(Ipv4Addr::from(u32::from_be((*sin).sin_addr.s_addr))))
+            },
+            libc::AF_INET6 => {
+                let sin6 = sa as *const libc::sockaddr_in6;
+                Some(IpAddr::V6(Ipv6Addr::from((*sin6).sin6_addr.s6_addr)))
+            },
+            // e.g. a unix socket
+            _ => None
+        }
+    }
+}
+
+// All the headers with the name, joined as if they were one
+fn get_joined_headers(request: &http::Request, name: &str) -> String {
+    request.headers_in_iterator()
+           .filter(|h| h.0.as_bytes().eq_ignore_ascii_case(name.as_bytes()))
+           .filter_map(|h| h.1.to_str().ok())
+           .collect::<Vec<&str>>()
+           .join(",")
+}
+
+// The address of the client, allowing for the proxies in front of nginx, which put it in 'header'.
+// None if it can't be known, e.g. a proxy hid it.
+pub fn get_client_ip(request: &http::Request, trusted_proxies: &[IpRange], header: RealIpHeader) -> Option<IpAddr> {
+    let peer = get_peer_ip(request)?;
+    if trusted_proxies.is_empty() {
+        return Some(peer.to_canonical());
+    }
+
+    let value = get_joined_headers(request, header.name());
+    let chain = if value.trim().is_empty() {
+        Vec::new()
+    } else {
+        header.parse_header(&value)
+    };
+
+    get_client(peer, &chain, trusted_proxies)
+}
+
+// Whether the client connected over TLS, to nginx or to a trusted proxy that says so.
+pub fn is_https(request: &http::Request, trusted_proxies: &[IpRange]) -> bool {
+    if unsafe { !(*request.connection()).ssl.is_null() } {
+        return true;
+    }
+
+    let from_proxy = get_peer_ip(request).map(|p| trusted_proxies.iter().any(|t| t.contains(p))).unwrap_or(false);
+    let proto = get_joined_headers(request, "X-Forwarded-Proto");
+    from_proxy && proto.split(',').next().map(|p| p.trim().eq_ignore_ascii_case("https")).unwrap_or(false)
+}
+
+// Is the client on the machine running nginx, over IPv4 or IPv6?
+pub fn is_localhost(request: &http::Request, trusted_proxies: &[IpRange], header: RealIpHeader) -> bool {
+    get_client_ip(request, trusted_proxies, header).map(|ip| ip.is_loopback()).unwrap_or(false)
+}
+
+// Whether the client is allowed by the first rule it matches, or None if none do.
+pub fn check_client(request: &http::Request, rules: &[AccessRule], trusted_proxies: &[IpRange], header: RealIpHeader) -> Option<bool> {
+    if rules.is_empty() {
+        return None;
+    }
+
+    check_rules(rules, get_client_ip(request, trusted_proxies, header))
+}
@@ -0,0 +1,53 @@
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use chrono::{ DateTime, Utc };
+
+// Validators for conditional GETs, i.e. so that browsers can revalidate rather than re-download.
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: u64    // seconds since the epoch
+}
+
+impl Validators {
+    // 'tag' distinguishes different responses generated from the same file, e.g. sizes of a photo.
+    pub fn new(tag: &str, last_modified: u64) -> Validators {
+        Validators {
+            etag: format!("\"{}\"", tag),
+            last_modified: last_modified
+        }
+    }
+
+    // Validators for a file served as is.
+    pub fn for_file(path: &Path) -> Option<Validators> {
+        let md = fs::metadata(path).ok()?;
+        let mtime = md.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+
+        Some(Validators::new(format!("{:x}-{:x}", mtime, md.len()).as_str(), mtime))
+    }
+
+    pub fn last_modified_header(&self) -> String {
+        let time = DateTime::<Utc>::from_timestamp(self.last_modified as i64, 0).unwrap_or_default();
+        time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+    }
+
+    // If-None-Match takes precedence over If-Modified-Since, per RFC 9110.
+    pub fn is_not_modified(&self, if_none_match: Option<&str>, if_modified_since: Option<&str>) -> bool {
+        if let Some(inm) = if_none_match {
+            return inm.split(',')
+                      .map(|t| t.trim())
+                      .any(|t| t == "*" || t.trim_start_matches("W/") == self.etag);
+        }
+
+        if let Some(ims) = if_modified_since {
+            return match DateTime::parse_from_rfc2822(ims) {
+                Ok(t) => t.timestamp() >= self.last_modified as i64,
+                Err(_) => false
+            };
+        }
+
+        false
+    }
+}
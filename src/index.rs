@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use chrono::NaiveDateTime;
+
+use once_cell::sync::Lazy;
+
+use crate::photos::{ load_metadata, Gallery, MD_FILE };
+
+static INDEX_TEMPLATE: &str = include_str!("../html/galleries.html");
+
+// Size of the cover thumbnails, which the browser scales to fit
+const COVER_WIDTH: u32 = 400;
+const COVER_HEIGHT: u32 = 300;
+
+// Summaries by metadata file, with the modified time and size of the metadata they were made
+// from, so every gallery's metadata isn't parsed for each request for the index.
+static SUMMARIES: Lazy<Mutex<HashMap<PathBuf, (SystemTime, u64, GallerySummary)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// A gallery as listed in the index.
+#[derive(Clone)]
+pub struct GallerySummary {
+    pub url: String,      // Relative to the index
+    pub title: String,
+    pub cover: String,
+    pub count: usize,
+    pub start: Option<NaiveDateTime>,
+    pub end: Option<NaiveDateTime>
+}
+
+impl GallerySummary {
+    // 'relative' is the gallery directory relative to the index directory.
+    pub fn new(relative: &Path, gallery: &Gallery) -> GallerySummary {
+        let url = relative.components()
+                          .map(|c| urlencoding::encode(&c.as_os_str().to_string_lossy()).into_owned())
+                          .collect::<Vec<String>>()
+                          .join("/");
+        let title = match gallery.gallery.title {
+            Some(ref t) => t.clone(),
+            None => relative.to_string_lossy().to_string()
+        };
+
+        GallerySummary {
+            url: format!("{}/", url),
+            title,
+            cover: gallery.cover(),
+            count: gallery.count(),
+            start: gallery.gallery.start.or_else(|| gallery.items.iter().map(|i| i.time).min()),
+            end: gallery.gallery.end.or_else(|| gallery.items.iter().map(|i| i.time).max())
+        }
+    }
+}
+
+// The summary of the gallery at 'relative' to 'dir', from the metadata unless it's unchanged.
+pub fn summarize(dir: &Path, relative: &Path) -> std::io::Result<GallerySummary> {
+    let path = dir.join(relative).join(MD_FILE);
+    let md = fs::metadata(&path)?;
+    let (modified, size) = (md.modified()?, md.len());
+    if let Some((m, s, summary)) = SUMMARIES.lock().unwrap().get(&path) {
+        if *m == modified && *s == size {
+            return Ok(summary.clone());
+        }
+    }
+
+    let summary = GallerySummary::new(relative, &load_metadata(&path)?);
+    SUMMARIES.lock().unwrap().insert(path, (modified, size, summary.clone()));
+    Ok(summary)
+}
+
+// Returns the directories beneath 'dir' with metadata, i.e. that are galleries, relative to 'dir'.
+pub fn find_galleries(dir: &Path) -> Vec<PathBuf> {
+    let mut galleries = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(relative) = dirs.pop() {
+        let entries = match fs::read_dir(dir.join(&relative)) {
+            Ok(e) => e,
+            Err(_) => continue
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            // Skip hidden directories, e.g. .git, and don't follow links so we can't loop
+            if name.to_string_lossy().starts_with('.') || !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                continue;
+            }
+            let path = relative.join(&name);
+            if dir.join(&path).join(MD_FILE).is_file() {
+                galleries.push(path);
+            } else {
+                dirs.push(path);
+            }
+        }
+    }
+
+    galleries
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+fn date_range(summary: &GallerySummary) -> String {
+    let format = |d: NaiveDateTime| d.format("%m/%d/%Y").to_string();
+    match (summary.start.map(format), summary.end.map(format)) {
+        (Some(s), Some(e)) if s != e => format!("{} - {}", s, e),
+        (Some(s), _) => s,
+        _ => String::new()
+    }
+}
+
+// Newest first, as that's usually what people are looking for.
+pub fn render_index(title: &str, mut galleries: Vec<GallerySummary>) -> String {
+    galleries.sort_by(|a, b| b.start.cmp(&a.start).then_with(|| a.title.cmp(&b.title)));
+
+    let mut items = String::new();
+    for g in &galleries {
+        let mut details = vec![format!("{} {}", g.count, if g.count == 1 { "item" } else { "items" })];
+        let dates = date_range(g);
+        if !dates.is_empty() {
+            details.insert(0, dates);
+        }
+
+        let url = escape(&g.url);
+        items.push_str(&format!("<a class=\"gallery\" href=\"{}\">", url));
+        let cover = g.cover.split('/').map(|c| urlencoding::encode(c).into_owned()).collect::<Vec<String>>().join("/");
+        items.push_str(&format!("<img src=\"{}{}.jpg?w={}&amp;h={}\" loading=\"lazy\" alt=\"\">", url, escape(&cover), COVER_WIDTH, COVER_HEIGHT));
+        items.push_str(&format!("<div class=\"title\">{}</div>", escape(&g.title)));
+        items.push_str(&format!("<div class=\"details\">{}</div>", details.join(" &middot; ")));
+        items.push_str("</a>\n");
+    }
+
+    INDEX_TEMPLATE.replace("{{title}}", &escape(title)).replace("{{galleries}}", &items)
+}
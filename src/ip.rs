@@ -0,0 +1,296 @@
+use std::net::IpAddr;
+
+// An address or network, e.g. '10.0.0.0/8', '::1' or '192.168.1.5', as given to the
+// IP-based directives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8
+}
+
+impl IpRange {
+    pub fn parse(s: &str) -> Option<IpRange> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p.parse::<u8>().ok()?)),
+            None => (s, None)
+        };
+        let network = address.parse::<IpAddr>().ok()?.to_canonical();
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return None;
+        }
+
+        Some(IpRange { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(n), IpAddr::V4(a)) => same_prefix(&n.octets(), &a.octets(), self.prefix),
+            (IpAddr::V6(n), IpAddr::V6(a)) => same_prefix(&n.octets(), &a.octets(), self.prefix),
+            _ => false
+        }
+    }
+}
+
+fn same_prefix(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let bytes = (prefix / 8) as usize;
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    let bits = prefix % 8;
+    if bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - bits);
+    a[bytes] & mask == b[bytes] & mask
+}
+
+// The addresses a request passed through, from a Forwarded header, e.g.
+// 'for=192.0.2.60;proto=http, for="[2001:db8:cafe::17]:4711"', oldest first. Obfuscated or
+// unknown addresses are None.
+pub fn parse_forwarded(header: &str) -> Vec<Option<IpAddr>> {
+    header.split(',')
+          .filter_map(|element| {
+              element.split(';')
+                     .filter_map(|pair| pair.trim().split_once('='))
+                     .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                     .map(|(_, value)| parse_node(value.trim().trim_matches('"')))
+          })
+          .collect()
+}
+
+// A node of a Forwarded header, which may have a port, and brackets if it's IPv6
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    match node.parse::<IpAddr>() {
+        Ok(ip) => Some(ip),
+        Err(_) => node.rsplit_once(':')?.0.parse().ok()
+    }
+}
+
+// The addresses from X-Forwarded-For headers, e.g. 'client, proxy1, proxy2', oldest first.
+pub fn parse_x_forwarded_for(header: &str) -> Vec<Option<IpAddr>> {
+    header.split(',').map(|a| parse_node(a.trim())).collect()
+}
+
+// The header, given to rust_gallery_real_ip_header, that the trusted proxies put the client's
+// address in. Only that one is read, as a client could send the other one itself.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RealIpHeader {
+    #[default]
+    XForwardedFor,
+    Forwarded
+}
+
+impl RealIpHeader {
+    pub fn parse(s: &str) -> Option<RealIpHeader> {
+        match s.to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Some(RealIpHeader::XForwardedFor),
+            "forwarded" => Some(RealIpHeader::Forwarded),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RealIpHeader::XForwardedFor => "X-Forwarded-For",
+            RealIpHeader::Forwarded => "Forwarded"
+        }
+    }
+
+    pub fn parse_header(&self, header: &str) -> Vec<Option<IpAddr>> {
+        match self {
+            RealIpHeader::XForwardedFor => parse_x_forwarded_for(header),
+            RealIpHeader::Forwarded => parse_forwarded(header)
+        }
+    }
+}
+
+// The client is the last address that isn't a trusted proxy, working back from the peer through
+// the addresses each proxy added. Anything before an untrusted address could have been made up.
+pub fn get_client(peer: IpAddr, forwarded: &[Option<IpAddr>], trusted_proxies: &[IpRange]) -> Option<IpAddr> {
+    let mut client = peer.to_canonical();
+    for address in forwarded.iter().rev() {
+        if !trusted_proxies.iter().any(|p| p.contains(client)) {
+            break;
+        }
+        client = (*address)?.to_canonical();
+    }
+
+    Some(client)
+}
+
+// A rule of rust_gallery_allow, rust_gallery_deny, or their edit_ equivalents. No range means
+// 'all', which also covers clients whose address isn't known.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccessRule {
+    allow: bool,
+    range: Option<IpRange>
+}
+
+impl AccessRule {
+    pub fn parse(allow: bool, s: &str) -> Option<AccessRule> {
+        if s == "all" {
+            return Some(AccessRule { allow, range: None });
+        }
+
+        Some(AccessRule { allow, range: Some(IpRange::parse(s)?) })
+    }
+
+    fn matches(&self, client: Option<IpAddr>) -> bool {
+        match (self.range, client) {
+            (None, _) => true,
+            (Some(range), Some(ip)) => range.contains(ip),
+            (Some(_), None) => false
+        }
+    }
+}
+
+// Whether the first rule the client matches allows it, as nginx's allow and deny do, or None if
+// none match.
+pub fn check_rules(rules: &[AccessRule], client: Option<IpAddr>) -> Option<bool> {
+    rules.iter().find(|r| r.matches(client)).map(|r| r.allow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn ranges(s: &[&str]) -> Vec<IpRange> {
+        s.iter().map(|r| IpRange::parse(r).unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(IpRange::parse("10.0.0.0/8"), Some(IpRange { network: ip("10.0.0.0"), prefix: 8 }));
+        assert_eq!(IpRange::parse("192.168.1.5"), Some(IpRange { network: ip("192.168.1.5"), prefix: 32 }));
+        assert_eq!(IpRange::parse("::1"), Some(IpRange { network: ip("::1"), prefix: 128 }));
+        assert_eq!(IpRange::parse("::ffff:10.1.2.3/16"), Some(IpRange { network: ip("10.1.2.3"), prefix: 16 }));
+        assert_eq!(IpRange::parse("10.0.0.0/33"), None);
+        assert_eq!(IpRange::parse("fd00::/129"), None);
+        assert_eq!(IpRange::parse("10.0.0.0/"), None);
+        assert_eq!(IpRange::parse("example.com"), None);
+        assert_eq!(IpRange::parse(""), None);
+    }
+
+    #[test]
+    fn ranges_contain_addresses() {
+        let network = IpRange::parse("192.168.0.0/16").unwrap();
+        assert!(network.contains(ip("192.168.255.1")));
+        assert!(network.contains(ip("::ffff:192.168.1.1")));
+        assert!(!network.contains(ip("192.169.0.1")));
+        assert!(!network.contains(ip("::1")));
+
+        let odd = IpRange::parse("10.0.0.0/9").unwrap();
+        assert!(odd.contains(ip("10.127.0.1")));
+        assert!(!odd.contains(ip("10.128.0.1")));
+
+        let v6 = IpRange::parse("fd00::/8").unwrap();
+        assert!(v6.contains(ip("fdab::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+
+        assert!(IpRange::parse("0.0.0.0/0").unwrap().contains(ip("203.0.113.9")));
+        assert!(IpRange::parse("::/0").unwrap().contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn compares_prefixes() {
+        assert!(same_prefix(&[10, 1, 2, 3], &[10, 1, 9, 9], 16));
+        assert!(!same_prefix(&[10, 1, 2, 3], &[10, 2, 2, 3], 16));
+        assert!(same_prefix(&[0b1010_0000], &[0b1011_1111], 3));
+        assert!(!same_prefix(&[0b1010_0000], &[0b1000_0000], 3));
+        assert!(same_prefix(&[1, 2, 3, 4], &[5, 6, 7, 8], 0));
+        assert!(same_prefix(&[1, 2, 3, 4], &[1, 2, 3, 4], 32));
+        assert!(!same_prefix(&[1, 2, 3, 4], &[1, 2, 3, 5], 32));
+    }
+
+    #[test]
+    fn parses_nodes() {
+        assert_eq!(parse_node("192.0.2.60"), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node("192.0.2.60:8080"), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node("[2001:db8:cafe::17]:4711"), Some(ip("2001:db8:cafe::17")));
+        assert_eq!(parse_node("[2001:db8:cafe::17]"), Some(ip("2001:db8:cafe::17")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node(""), None);
+    }
+
+    #[test]
+    fn parses_forwarded() {
+        assert_eq!(parse_forwarded("for=192.0.2.60;proto=http;by=203.0.113.43"), vec![Some(ip("192.0.2.60"))]);
+        assert_eq!(parse_forwarded("for=192.0.2.43, FOR=\"[2001:db8:cafe::17]:4711\""),
+                   vec![Some(ip("192.0.2.43")), Some(ip("2001:db8:cafe::17"))]);
+        assert_eq!(parse_forwarded("for=unknown, for=198.51.100.17"), vec![None, Some(ip("198.51.100.17"))]);
+        assert_eq!(parse_forwarded("proto=https, for=198.51.100.17"), vec![Some(ip("198.51.100.17"))]);
+        assert_eq!(parse_forwarded(""), vec![]);
+    }
+
+    #[test]
+    fn parses_x_forwarded_for() {
+        assert_eq!(parse_x_forwarded_for("203.0.113.7, 10.0.0.2"), vec![Some(ip("203.0.113.7")), Some(ip("10.0.0.2"))]);
+        assert_eq!(parse_x_forwarded_for("junk,10.0.0.2"), vec![None, Some(ip("10.0.0.2"))]);
+    }
+
+    #[test]
+    fn reads_only_the_configured_header() {
+        assert_eq!(RealIpHeader::parse("x-forwarded-for"), Some(RealIpHeader::XForwardedFor));
+        assert_eq!(RealIpHeader::parse("Forwarded"), Some(RealIpHeader::Forwarded));
+        assert_eq!(RealIpHeader::parse("X-Real-IP"), None);
+        assert_eq!(RealIpHeader::default().name(), "X-Forwarded-For");
+        assert_eq!(RealIpHeader::Forwarded.parse_header("for=203.0.113.7"), vec![Some(ip("203.0.113.7"))]);
+        assert_eq!(RealIpHeader::XForwardedFor.parse_header("for=203.0.113.7"), vec![None]);
+    }
+
+    #[test]
+    fn finds_the_client_behind_trusted_proxies() {
+        let trusted = ranges(&["10.0.0.0/8", "::1"]);
+        let chain = vec![Some(ip("203.0.113.7")), Some(ip("10.0.0.2"))];
+        assert_eq!(get_client(ip("10.0.0.1"), &chain, &trusted), Some(ip("203.0.113.7")));
+        assert_eq!(get_client(ip("::1"), &chain, &trusted), Some(ip("203.0.113.7")));
+        assert_eq!(get_client(ip("::ffff:10.0.0.1"), &chain, &trusted), Some(ip("203.0.113.7")));
+        assert_eq!(get_client(ip("10.0.0.1"), &[], &trusted), Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn ignores_what_untrusted_clients_add() {
+        let trusted = ranges(&["10.0.0.0/8"]);
+        // The client made up 127.0.0.1, and the proxy added the address it came from
+        let chain = vec![Some(ip("127.0.0.1")), Some(ip("198.51.100.4"))];
+        assert_eq!(get_client(ip("10.0.0.1"), &chain, &trusted), Some(ip("198.51.100.4")));
+        // Not from a proxy at all
+        assert_eq!(get_client(ip("198.51.100.4"), &[Some(ip("127.0.0.1"))], &trusted), Some(ip("198.51.100.4")));
+        // Every address trusted, so the oldest is the client
+        let chain = vec![Some(ip("10.0.0.3")), Some(ip("10.0.0.2"))];
+        assert_eq!(get_client(ip("10.0.0.1"), &chain, &trusted), Some(ip("10.0.0.3")));
+    }
+
+    #[test]
+    fn hidden_client_is_unknown() {
+        let trusted = ranges(&["10.0.0.0/8"]);
+        assert_eq!(get_client(ip("10.0.0.1"), &[None], &trusted), None);
+        assert_eq!(get_client(ip("10.0.0.1"), &[Some(ip("203.0.113.7")), None], &trusted), None);
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = vec![AccessRule::parse(false, "192.168.1.13").unwrap(),
+                         AccessRule::parse(true, "192.168.0.0/16").unwrap(),
+                         AccessRule::parse(false, "all").unwrap()];
+        assert_eq!(check_rules(&rules, Some(ip("192.168.1.13"))), Some(false));
+        assert_eq!(check_rules(&rules, Some(ip("192.168.1.14"))), Some(true));
+        assert_eq!(check_rules(&rules, Some(ip("203.0.113.7"))), Some(false));
+        assert_eq!(check_rules(&rules, None), Some(false));
+        assert_eq!(check_rules(&rules[..2], None), None);
+        assert_eq!(check_rules(&[], Some(ip("203.0.113.7"))), None);
+        assert_eq!(AccessRule::parse(true, "everyone"), None);
+    }
+}
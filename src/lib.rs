@@ -1,8 +1,11 @@
 use ngx::ffi::{
     ngx_array_push, ngx_buf_t, ngx_chain_t, ngx_command_t, ngx_conf_log_error, ngx_conf_t,
-    ngx_http_handler_pt, ngx_http_module_t, ngx_http_phases_NGX_HTTP_CONTENT_PHASE, ngx_int_t,
-    ngx_module_t, ngx_uint_t, NGX_CONF_NOARGS, NGX_HTTP_LOC_CONF, NGX_HTTP_LOC_CONF_OFFSET,
-    NGX_HTTP_MODULE, NGX_LOG_ERR,
+    ngx_event_t, ngx_http_finalize_request, ngx_http_handler_pt, ngx_http_module_t,
+    ngx_http_phases_NGX_HTTP_CONTENT_PHASE, ngx_http_read_client_request_body, ngx_http_request_t,
+    ngx_http_run_posted_requests, ngx_int_t, ngx_log_t, ngx_module_t, ngx_pool_cleanup_add, ngx_str_t, ngx_thread_pool_add,
+    ngx_thread_pool_t, ngx_thread_task_alloc, ngx_thread_task_post, ngx_uint_t, NGX_CONF_1MORE,
+    NGX_CONF_FLAG, NGX_CONF_NOARGS, NGX_CONF_TAKE1, NGX_HTTP_LOC_CONF, NGX_HTTP_LOC_CONF_OFFSET, NGX_HTTP_MODULE,
+    NGX_HTTP_SPECIAL_RESPONSE, NGX_LOG_EMERG, NGX_LOG_ERR,
 };
 use ngx::http::{
     HttpModule, HttpModuleLocationConf, HttpModuleMainConf, 
@@ -20,35 +23,82 @@ use ::http::Uri;
 use std::cmp::max;
 use std::collections::HashMap;
 use std::ffi::CString;
-use std::fs::read_to_string;
+use std::fs::{ read_to_string, File };
+use std::io::{ BufRead, BufReader };
 use std::io::Write;
-use std::path::PathBuf;
+use std::panic::{ catch_unwind, AssertUnwindSafe };
+use std::path::{ Path, PathBuf };
 use std::sync::RwLock;
 use std::time::Instant;
 
 use serde::{ Serialize };
 
+use image::imageops::FilterType;
+
 use once_cell::sync::Lazy;
 
 use uuid::Uuid;
 
 use urlencoding;
 
-mod localhost;
+mod auth;
+
+use auth::{ csrf_token, DUMMY_HASH, is_shared, is_valid_csrf_token, is_valid_share, make_session, now, parse_editor, session_key, session_user, SESSION_COOKIE, SESSION_DURATION, SHARE_COOKIE };
+
+mod cache;
+
+use cache::{ parse_size, source_stat, Rendition, RenditionCache };
+
+mod caption;
+
+use caption::{ parse_caption, CaptionError, DEFAULT_MAX_LENGTH };
+
+mod conditional;
+
+use conditional::Validators;
 
-use localhost::is_localhost;
+mod index;
+
+use index::{ find_galleries, render_index, summarize };
+
+mod client;
+
+use client::{ check_client, is_https, is_localhost };
+
+mod ip;
+
+use ip::{ AccessRule, IpRange, RealIpHeader };
 
 mod photos;
 
 pub use photos::MD_FILE;
 pub use photos::Image;
+pub use photos::load_metadata;
+pub use photos::save_metadata;
+pub use photos::save_metadata_keeping_captions;
+pub use photos::Gallery;
+pub use photos::GalleryInfo;
+pub use photos::Album;
+pub use photos::GpsPosition;
+pub use photos::MediaKind;
+pub use photos::is_transposed;
 pub use photos::make_preview;
+pub use photos::file_arg;
+pub use photos::Progress;
+pub use photos::parse_filter;
+pub use photos::ResizeOptions;
+pub use photos::OutputFormat;
+
+pub use auth::PasswordHash;
+pub use auth::read_secret;
+pub use auth::share_signature;
+pub use auth::is_valid_user;
 
 use photos::as_preview;
 use photos::as_scaled;
 use photos::is_jpg;
+use photos::is_photo;
 use photos::load_file;
-use photos::load_metadata;
 use photos::is_mp4;
 use photos::resize_image;
 use photos::update_caption;
@@ -56,7 +106,16 @@ use photos::update_caption;
 struct Module;
 
 // Store the metadata in RAM so we don't have to reparse everything on each request.
-static IMAGES: Lazy<RwLock<HashMap<String, Vec<Image>>>> = Lazy::new(|| RwLock::new(HashMap::<String, Vec<Image>>::new()));
+static IMAGES: Lazy<RwLock<HashMap<String, LoadedGallery>>> = Lazy::new(|| RwLock::new(HashMap::<String, LoadedGallery>::new()));
+
+// A gallery's metadata, and the validators of the file it was read from, which tell when
+// make-gallery has rewritten it.
+struct LoadedGallery {
+    gallery: Gallery,
+    validators: Option<Validators>
+}
+
+static LOGIN_TEMPLATE: &str = include_str!("../html/login.html");
 
 // Most of the boilerplate nginx code uses https://github.com/f5yacobucci/ngx-rust-howto as an example.
 
@@ -99,7 +158,58 @@ unsafe impl http::HttpModuleLocationConf for Module {
 #[derive(Debug, Default)]
 struct ModuleConfig {
     enabled: bool,
-    root: String           // root path for files to be served
+    root: String,                                   // root path for files to be served
+    thread_pool: Option<*mut ngx_thread_pool_t>,    // where images are resized
+    cache_dir: String,                              // where resized images are cached, if anywhere
+    cache_max_size: u64,
+    sizes: Vec<u32>,                                // sorted sizes that requested dimensions are rounded up to
+    cache_control: String,                          // Cache-Control header for photos and metadata
+    jpeg_quality: u8,                               // 0 if not configured
+    caption_max_length: usize,                      // 0 if not configured
+    resize_filter: Option<FilterType>,
+    formats: Vec<OutputFormat>,                     // formats other than jpeg to serve if accepted, in preference order
+    passwords: Vec<PasswordHash>,                   // any of which signs in; anyone can view if there are none
+    session_path: String,                           // Path of the session cookie, i.e. the location
+    share_secret: Vec<u8>,                          // what share links are signed with, if they're allowed
+    editors: Vec<(String, PasswordHash)>,           // who can sign in to edit captions
+    edit_from_localhost: Option<bool>,              // whether anyone on this machine can edit captions, by default yes
+    trusted_proxies: Vec<IpRange>,                  // whose real_ip_header is believed
+    real_ip_header: Option<RealIpHeader>,           // where trusted proxies put the client's address, by default X-Forwarded-For
+    access_rules: Vec<AccessRule>,                  // who can see the location without signing in, or not at all
+    edit_rules: Vec<AccessRule>                     // who can edit captions, before editors and localhost
+}
+
+impl ModuleConfig {
+    fn resize_options(&self) -> ResizeOptions {
+        let mut options = ResizeOptions::default();
+        if self.jpeg_quality != 0 {
+            options.quality = self.jpeg_quality;
+        }
+        if let Some(f) = self.resize_filter {
+            options.filter = f;
+        }
+
+        options
+    }
+
+    // Only those signed in, or with a share link, can see a private location.
+    fn is_private(&self) -> bool {
+        !self.passwords.is_empty() || !self.share_secret.is_empty()
+    }
+
+    fn has_login(&self) -> bool {
+        !self.passwords.is_empty() || !self.editors.is_empty()
+    }
+
+    // Viewers' sessions, with no user, are signed with the passwords, and editors' with their own
+    // hash. None if the user can't sign in, as anyone could sign a session with no key.
+    fn session_key(&self, user: &str) -> Option<Vec<u8>> {
+        if user.is_empty() {
+            Some(session_key(self.passwords.iter())).filter(|_| !self.passwords.is_empty())
+        } else {
+            self.editors.iter().find(|e| e.0 == user).map(|e| session_key(std::iter::once(&e.1)))
+        }
+    }
 }
 
 impl http::Merge for ModuleConfig {
@@ -116,6 +226,83 @@ impl http::Merge for ModuleConfig {
             });
         }
 
+        if self.thread_pool.is_none() {
+            self.thread_pool = prev.thread_pool;
+        }
+
+        if self.cache_dir.is_empty() {
+            self.cache_dir = prev.cache_dir.clone();
+        }
+
+        if self.cache_max_size == 0 {
+            self.cache_max_size = if prev.cache_max_size != 0 {
+                prev.cache_max_size
+            } else {
+                cache::DEFAULT_MAX_SIZE
+            };
+        }
+
+        if self.sizes.is_empty() {
+            self.sizes = prev.sizes.clone();
+        }
+
+        if self.cache_control.is_empty() {
+            self.cache_control = prev.cache_control.clone();
+        }
+
+        if self.jpeg_quality == 0 {
+            self.jpeg_quality = prev.jpeg_quality;
+        }
+
+        if self.caption_max_length == 0 {
+            self.caption_max_length = prev.caption_max_length;
+        }
+
+        if self.resize_filter.is_none() {
+            self.resize_filter = prev.resize_filter;
+        }
+
+        if self.formats.is_empty() {
+            self.formats = prev.formats.clone();
+        }
+
+        if self.passwords.is_empty() {
+            self.passwords = prev.passwords.clone();
+        }
+
+        if self.editors.is_empty() {
+            self.editors = prev.editors.clone();
+        }
+
+        if self.session_path.is_empty() {
+            self.session_path = prev.session_path.clone();
+        }
+
+        if self.edit_from_localhost.is_none() {
+            self.edit_from_localhost = prev.edit_from_localhost;
+        }
+
+        if self.trusted_proxies.is_empty() {
+            self.trusted_proxies = prev.trusted_proxies.clone();
+        }
+
+        if self.real_ip_header.is_none() {
+            self.real_ip_header = prev.real_ip_header;
+        }
+
+        // As nginx's allow and deny, a location's rules replace rather than add to those it inherits
+        if self.access_rules.is_empty() {
+            self.access_rules = prev.access_rules.clone();
+        }
+
+        if self.edit_rules.is_empty() {
+            self.edit_rules = prev.edit_rules.clone();
+        }
+
+        if self.share_secret.is_empty() {
+            self.share_secret = prev.share_secret.clone();
+        }
+
         if self.enabled && self.root.is_empty() {
             return Err(MergeConfigError::NoValue);
         }
@@ -153,7 +340,7 @@ pub static mut ngx_http_rust_gallery_module: ngx_module_t = ngx_module_t {
 
 // Register and allocate our command structures for directive generation and eventual storage.
 #[no_mangle]
-static mut ngx_http_rust_gallery_commands: [ngx_command_t; 2] = [
+static mut ngx_http_rust_gallery_commands: [ngx_command_t; 21] = [
     ngx_command_t {
         name: ngx_string!("rust_gallery"),
         type_: (NGX_HTTP_LOC_CONF | NGX_CONF_NOARGS) as ngx_uint_t,
@@ -162,9 +349,173 @@ static mut ngx_http_rust_gallery_commands: [ngx_command_t; 2] = [
         offset: 0,
         post: std::ptr::null_mut(),
     },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_cache"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_cache_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_cache_max_size"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_cache_max_size_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_sizes"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_sizes_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_cache_control"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_cache_control_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_jpeg_quality"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_jpeg_quality_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_caption_max_length"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_caption_max_length_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_resize_filter"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_resize_filter_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_formats"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_formats_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_password"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_password_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_password_file"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_password_file_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_share_secret_file"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_share_secret_file_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_editors_file"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_editors_file_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_edit_from_localhost"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_FLAG) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_edit_from_localhost_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_trusted_proxies"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_1MORE) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_trusted_proxies_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_real_ip_header"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_real_ip_header_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_allow"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_allow_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_deny"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_deny_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_edit_allow"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_edit_allow_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
+    ngx_command_t {
+        name: ngx_string!("rust_gallery_edit_deny"),
+        type_: (NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1) as ngx_uint_t,
+        set: Some(ngx_http_rust_gallery_edit_deny_set),
+        conf: NGX_HTTP_LOC_CONF_OFFSET,
+        offset: 0,
+        post: std::ptr::null_mut(),
+    },
     ngx_command_t::empty(),
 ];
 
+// The directive's arguments, not including the directive name.
+unsafe fn get_args(cf: *mut ngx_conf_t) -> Vec<String> {
+    let args = (*(*cf).args).elts as *const ngx_str_t;
+    (1..(*(*cf).args).nelts).map(|i| (*args.add(i)).to_string()).collect()
+}
+
+unsafe fn conf_error(cf: *mut ngx_conf_t, msg: String) -> *mut c_char {
+    let err = CString::new(msg).unwrap();
+    ngx_conf_log_error(NGX_LOG_EMERG as usize, cf, 0, err.as_ptr() as *const c_char);
+    core::NGX_CONF_ERROR
+}
+
 #[no_mangle]
 extern "C" fn ngx_http_rust_gallery_commands_set_method(
     cf: *mut ngx_conf_t,
@@ -181,10 +532,394 @@ extern "C" fn ngx_http_rust_gallery_commands_set_method(
             ngx_conf_log_error(NGX_LOG_ERR as usize, cf, 0, err.as_ptr() as *const c_char);
         }
         conf.root = (*lc).root.to_string();
+
+        // The 'default' thread pool is created by nginx if it isn't configured explicitly.
+        let tp = ngx_thread_pool_add(cf, std::ptr::null_mut());
+        if tp.is_null() {
+            let err = CString::new(format!("Unable to get the default thread pool for location {}", (*lc).name)).unwrap();
+            ngx_conf_log_error(NGX_LOG_ERR as usize, cf, 0, err.as_ptr() as *const c_char);
+        } else {
+            conf.thread_pool = Some(tp);
+        }
     };
 
     std::ptr::null_mut()
 }
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_cache_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        conf.cache_dir = get_args(cf).remove(0);
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_cache_max_size_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        let size = get_args(cf).remove(0);
+        conf.cache_max_size = match parse_size(&size) {
+            Some(s) if s > 0 => s,
+            _ => { return conf_error(cf, format!("Invalid rust_gallery_cache_max_size {}", size)); }
+        };
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_sizes_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        for size in get_args(cf) {
+            match size.parse::<u32>() {
+                Ok(s) if s > 0 => conf.sizes.push(s),
+                _ => { return conf_error(cf, format!("Invalid rust_gallery_sizes value {}", size)); }
+            }
+        }
+        conf.sizes.sort();
+        conf.sizes.dedup();
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_cache_control_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        conf.cache_control = get_args(cf).remove(0);
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_jpeg_quality_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        let quality = get_args(cf).remove(0);
+        conf.jpeg_quality = match quality.parse::<u8>() {
+            Ok(q) if q >= 1 && q <= 100 => q,
+            _ => { return conf_error(cf, format!("Invalid rust_gallery_jpeg_quality {}; must be 1 to 100", quality)); }
+        };
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_caption_max_length_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        let length = get_args(cf).remove(0);
+        conf.caption_max_length = match length.parse::<usize>() {
+            Ok(l) if l >= 1 => l,
+            _ => { return conf_error(cf, format!("Invalid rust_gallery_caption_max_length {}; must be a number of characters", length)); }
+        };
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_resize_filter_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        let filter = get_args(cf).remove(0);
+        conf.resize_filter = match parse_filter(&filter) {
+            Some(f) => Some(f),
+            None => { return conf_error(cf, format!("Invalid rust_gallery_resize_filter {}; must be one of nearest, triangle, catmullrom, gaussian or lanczos3", filter)); }
+        };
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_formats_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        for name in get_args(cf) {
+            match OutputFormat::parse(&name) {
+                Some(f) if f.is_available() => conf.formats.push(f),
+                Some(_) => { return conf_error(cf, format!("rust_gallery was built without support for {}", name)); }
+                None => { return conf_error(cf, format!("Invalid rust_gallery_formats value {}; must be webp or avif", name)); }
+            }
+        }
+    };
+
+    std::ptr::null_mut()
+}
+
+// The session cookie is limited to the location, unless it's a regular expression
+unsafe fn set_session_path(cf: *mut ngx_conf_t, conf: &mut ModuleConfig) {
+    let lc = NgxHttpCoreModule::location_conf(&*cf).expect("http core loc conf");
+    let name = (*lc).name.to_string();
+    conf.session_path = if name.starts_with('/') { name } else { String::from("/") };
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_password_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        for hash in get_args(cf) {
+            match PasswordHash::parse(&hash) {
+                Some(p) => conf.passwords.push(p),
+                None => { return conf_error(cf, format!("Invalid rust_gallery_password {}; use the output of 'make-gallery hash-password'", hash)); }
+            }
+        }
+        set_session_path(cf, conf);
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_password_file_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        let path = get_args(cf).remove(0);
+        let file = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) => { return conf_error(cf, format!("Unable to read rust_gallery_password_file {}: {}", path, e)); }
+        };
+
+        // A hash per line, as written by 'make-gallery hash-password', and comments
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => { return conf_error(cf, format!("Unable to read rust_gallery_password_file {}: {}", path, e)); }
+            };
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            match PasswordHash::parse(&line) {
+                Some(p) => conf.passwords.push(p),
+                None => { return conf_error(cf, format!("Invalid password hash on line {} of {}", n + 1, path)); }
+            }
+        }
+        if conf.passwords.is_empty() {
+            return conf_error(cf, format!("rust_gallery_password_file {} has no passwords", path));
+        }
+        set_session_path(cf, conf);
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_share_secret_file_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        let path = get_args(cf).remove(0);
+        conf.share_secret = match read_secret(Path::new(&path)) {
+            Ok(s) => s,
+            Err(e) => { return conf_error(cf, format!("Invalid rust_gallery_share_secret_file: {}", e)); }
+        };
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_editors_file_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        let path = get_args(cf).remove(0);
+        let file = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) => { return conf_error(cf, format!("Unable to read rust_gallery_editors_file {}: {}", path, e)); }
+        };
+
+        // 'name:hash' per line, as written by 'make-gallery hash-password --user name', and comments
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => { return conf_error(cf, format!("Unable to read rust_gallery_editors_file {}: {}", path, e)); }
+            };
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            match parse_editor(&line) {
+                Some(e) if conf.editors.iter().any(|x| x.0 == e.0) => { return conf_error(cf, format!("{} is on more than one line of {}", e.0, path)); }
+                Some(e) => conf.editors.push(e),
+                None => { return conf_error(cf, format!("Invalid editor on line {} of {}; should be name:hash", n + 1, path)); }
+            }
+        }
+        if conf.editors.is_empty() {
+            return conf_error(cf, format!("rust_gallery_editors_file {} has no editors", path));
+        }
+        set_session_path(cf, conf);
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_edit_from_localhost_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        conf.edit_from_localhost = match get_args(cf).remove(0).as_str() {
+            "on" => Some(true),
+            "off" => Some(false),
+            v => { return conf_error(cf, format!("Invalid rust_gallery_edit_from_localhost {}; must be on or off", v)); }
+        };
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_trusted_proxies_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        for proxy in get_args(cf) {
+            match IpRange::parse(&proxy) {
+                Some(r) => conf.trusted_proxies.push(r),
+                None => { return conf_error(cf, format!("Invalid rust_gallery_trusted_proxies value {}; must be an address or network, e.g. 10.0.0.0/8", proxy)); }
+            }
+        }
+    };
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_real_ip_header_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        let header = get_args(cf).remove(0);
+        conf.real_ip_header = match RealIpHeader::parse(&header) {
+            Some(h) => Some(h),
+            None => { return conf_error(cf, format!("Invalid rust_gallery_real_ip_header {}; must be X-Forwarded-For or Forwarded", header)); }
+        };
+    };
+
+    std::ptr::null_mut()
+}
+
+// Adds an allow or deny rule, for seeing the location or editing its captions
+unsafe fn add_access_rule(cf: *mut ngx_conf_t, rules: &mut Vec<AccessRule>, allow: bool, directive: &str) -> *mut c_char {
+    let value = get_args(cf).remove(0);
+    match AccessRule::parse(allow, &value) {
+        Some(r) => rules.push(r),
+        None => { return conf_error(cf, format!("Invalid {} value {}; must be an address, a network such as 192.168.0.0/16, or all", directive, value)); }
+    }
+
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_allow_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        add_access_rule(cf, &mut conf.access_rules, true, "rust_gallery_allow")
+    }
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_deny_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        add_access_rule(cf, &mut conf.access_rules, false, "rust_gallery_deny")
+    }
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_edit_allow_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        add_access_rule(cf, &mut conf.edit_rules, true, "rust_gallery_edit_allow")
+    }
+}
+
+#[no_mangle]
+extern "C" fn ngx_http_rust_gallery_edit_deny_set(
+    cf: *mut ngx_conf_t,
+    _cmd: *mut ngx_command_t,
+    conf: *mut c_void,
+) -> *mut c_char {
+    unsafe {
+        let conf = &mut *(conf as *mut ModuleConfig);
+        add_access_rule(cf, &mut conf.edit_rules, false, "rust_gallery_edit_deny")
+    }
+}
 // End of nginx boilerplate
 
 fn return_value(request: &mut http::Request, s: &str, content_type: &str) -> core::Status
@@ -205,6 +940,36 @@ fn return_value_with_status(request: &mut http::Request, s: &str, content_type:
     request.output_filter(&mut out)
 }
 
+fn get_header<'a>(request: &'a http::Request, name: &str) -> Option<&'a str> {
+    request.headers_in_iterator()
+           .find(|h| h.0.as_bytes().eq_ignore_ascii_case(name.as_bytes()))
+           .and_then(|h| h.1.to_str().ok())
+}
+
+fn add_validator_headers(request: &mut http::Request, validators: &Validators) {
+    request.add_header_out("ETag", validators.etag.as_str());
+    request.add_header_out("Last-Modified", validators.last_modified_header().as_str());
+
+    let cache_control = &Module::location_conf(request).expect("Module config exists").cache_control;
+    if !cache_control.is_empty() {
+        request.add_header_out("Cache-Control", cache_control.as_str());
+    }
+}
+
+// Sends a 304 if the client already has the current version.
+fn return_if_not_modified(request: &mut http::Request, validators: &Validators) -> Option<core::Status> {
+    if !validators.is_not_modified(get_header(request, "If-None-Match"), get_header(request, "If-Modified-Since")) {
+        return None;
+    }
+
+    request.set_status(HTTPStatus::NOT_MODIFIED);
+    add_validator_headers(request, validators);
+    // The crumb is a session cookie, so a cached page needs it again after the browser restarts
+    add_crumb(request);
+    request.as_mut().set_header_only(1);
+    Some(request.send_header())
+}
+
 fn parse_query_string(query: &str) -> HashMap<String, String> {
     query
         .split('&')
@@ -215,6 +980,34 @@ fn parse_query_string(query: &str) -> HashMap<String, String> {
         .collect()
 }
 
+// The first of 'formats' that the browser accepts, or jpeg if none are.
+fn negotiate_format(accept: Option<&str>, formats: &Vec<OutputFormat>) -> OutputFormat {
+    let accepted: Vec<&str> = match accept {
+        Some(a) => a.split(',')
+                    .filter(|t| !t.split(';').skip(1).any(|p| p.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()) == Some(0.0)))
+                    .map(|t| t.split(';').next().unwrap_or("").trim())
+                    .collect(),
+        None => Vec::new()
+    };
+
+    for format in formats {
+        if accepted.contains(&format.content_type()) {
+            return *format;
+        }
+    }
+
+    OutputFormat::Jpeg
+}
+
+// Round a requested dimension up to the nearest configured size so that the same
+// rendition is served to similar screens. 'sizes' is sorted.
+fn snap_to_size(requested: u32, sizes: &Vec<u32>) -> u32 {
+    match sizes.iter().find(|s| **s >= requested) {
+        Some(s) => *s,
+        None => *sizes.last().unwrap_or(&requested)
+    }
+}
+
 #[derive(Serialize,Debug)]
 struct Metadata<'a> {
     pub date: String,
@@ -223,10 +1016,46 @@ struct Metadata<'a> {
     pub location: &'a Option<String>
 }
 
+#[derive(Serialize,Debug)]
+struct GalleryMetadata<'a> {
+    pub title: &'a Option<String>,
+    pub description: &'a Option<String>,
+    pub author: &'a Option<String>,
+    pub cover: String,
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub albums: Vec<AlbumMetadata<'a>>,
+    pub parent: &'a Option<String>
+}
+
+#[derive(Serialize,Debug)]
+struct AlbumMetadata<'a> {
+    pub path: &'a String,
+    pub title: &'a String,
+    pub cover: &'a String,
+    pub count: usize,
+    pub start: Option<String>,
+    pub end: Option<String>
+}
+
 // Metadata used by the web-page
 fn return_metadata(request: &mut http::Request, gallery_path: &String) -> core::Status {
     let map = IMAGES.read().unwrap();
-    let imgs = map.get(gallery_path).expect("metadata doesn't exist");
+    let loaded = match map.get(gallery_path) {
+        Some(l) => l,
+        None => { return return_value_with_status(request, "The gallery metadata can't be read", "text/plain", HTTPStatus::INTERNAL_SERVER_ERROR); }
+    };
+
+    // Those of the file the metadata was read from, rather than the file now
+    if let Some(v) = &loaded.validators {
+        if let Some(status) = return_if_not_modified(request, v) {
+            return status;
+        }
+        add_validator_headers(request, v);
+    }
+
+    let gallery = &loaded.gallery;
+    let imgs = &gallery.items;
     let mut metadata = Vec::<Metadata>::with_capacity(imgs.len());
     for img in imgs.iter() {
         metadata.push(Metadata { 
@@ -237,7 +1066,28 @@ fn return_metadata(request: &mut http::Request, gallery_path: &String) -> core::
         });
     }
 
-    return_value(request, format!("const metadata = {};", serde_json::to_string(&metadata).unwrap()).as_str(), "application/javascript")
+    let info = &gallery.gallery;
+    let gallery_metadata = GalleryMetadata {
+        title: &info.title,
+        description: &info.description,
+        author: &info.author,
+        cover: gallery.cover(),
+        start: info.start.map(|d| d.format("%m/%d/%Y").to_string()),
+        end: info.end.map(|d| d.format("%m/%d/%Y").to_string()),
+        albums: info.albums.iter().map(|a| AlbumMetadata {
+            path: &a.path,
+            title: &a.title,
+            cover: &a.cover,
+            count: a.count,
+            start: a.start.map(|d| d.format("%m/%d/%Y").to_string()),
+            end: a.end.map(|d| d.format("%m/%d/%Y").to_string())
+        }).collect(),
+        parent: &info.parent
+    };
+
+    return_value(request, format!("const gallery = {};\nconst metadata = {};",
+                                  serde_json::to_string(&gallery_metadata).unwrap(),
+                                  serde_json::to_string(&metadata).unwrap()).as_str(), "application/javascript")
 }
 
 // Return '12' from '12.jpg' (for example)
@@ -257,30 +1107,22 @@ enum FileType {
     MP4
 }
 
-fn get_filename_from_id(gallery_path: &String, id: usize, file_type: FileType) -> String {
+// None if there's no such id, or the gallery has been unloaded since it was loaded
+fn get_filename_from_id(gallery_path: &String, id: usize, file_type: FileType) -> Option<String> {
     let map = IMAGES.read().unwrap();
-    let images = map.get(gallery_path).expect("metadata exists");
-    let image = images.get(id).expect("Image index in range");
+    let image = map.get(gallery_path)?.gallery.items.get(id)?;
     if image.is_mp4() {
         if file_type == FileType::MP4 {
             if image.mp4_scaled {
-                return as_scaled(&image.path);
+                return Some(as_scaled(&image.path));
             } else {
-                return format!("{}", image.path);
+                return Some(format!("{}", image.path));
             }
         } else {
-            return as_preview(&image.path);
+            return Some(as_preview(&image.path));
         }
     }
-    return format!("{}", image.path);
-}
-
-fn get_file_path(gallery_path: &String, id: usize, file_type: FileType) -> PathBuf {
-    let mut file_path = PathBuf::from(gallery_path);
-    let file_name = get_filename_from_id(gallery_path, id, file_type);
-    file_path.push(file_name.as_str());
-
-    file_path
+    return Some(format!("{}", image.path));
 }
 
 // Used to avoid memory copies; copy directly into nginx buffers
@@ -344,51 +1186,97 @@ fn get_content_type(file_name: &str) -> &str {
     ""
 }
 
+// Cookies may be in any of the Cookie headers, which are 'name=value; name=value'. There may be
+// more than one with a name, for different paths.
+fn get_cookies<'a>(request: &'a http::Request, name: &str) -> Vec<&'a str> {
+    request.headers_in_iterator()
+           .filter(|h| h.0.as_bytes().eq_ignore_ascii_case(b"Cookie"))
+           .filter_map(|h| h.1.to_str().ok())
+           .flat_map(|c| c.split(';'))
+           .filter_map(|c| c.trim().split_once('='))
+           .filter(|c| c.0 == name)
+           .map(|c| c.1)
+           .collect()
+}
+
+fn get_cookie<'a>(request: &'a http::Request, name: &str) -> Option<&'a str> {
+    get_cookies(request, name).first().copied()
+}
+
 // Get CSRF crumb for caption edit
 fn get_crumb(request: &http::Request) -> &str {
-    let mut i = request.headers_in_iterator();
-    loop {
-        match i.next() {
-            Some(h) => {
-                if h.0 == "Cookie" {
-                    if h.1.to_str().unwrap_or("").starts_with("crumb=") {
-                        let (_, crumb) = h.1.to_str().unwrap().split_at("crumb=".len());
-                        return crumb;
-                    }
-                }
-            },
-            None => {
-                return "";
-            }
-        }
+    get_cookie(request, "crumb").unwrap_or("")
+}
+
+// What the CSRF token is bound to, besides the crumb
+fn get_session(request: &http::Request) -> &str {
+    get_cookie(request, SESSION_COOKIE).unwrap_or("")
+}
+
+// The attributes every cookie has. The crumb is Strict, but the session and share cookies are
+// Lax, so following a link to the gallery from elsewhere doesn't need signing in again. Neither
+// is sent with another site's POSTs.
+fn cookie_attributes(request: &http::Request, same_site: &str) -> String {
+    let co = Module::location_conf(request).expect("Module config exists");
+    let secure = if is_https(request, &co.trusted_proxies) { "; Secure" } else { "" };
+    format!("HttpOnly; SameSite={}{}", same_site, secure)
+}
+
+// Whether the Origin, or Referer if there isn't one, is this site. Browsers send Origin with
+// POSTs, so one with neither isn't from another site's page.
+fn is_same_origin(request: &http::Request) -> bool {
+    let source = match get_header(request, "Origin").or_else(|| get_header(request, "Referer")) {
+        Some(s) => s,
+        None => return true
+    };
+    let host = get_header(request, "Host").unwrap_or("");
+    match source.split_once("://") {
+        Some((_, rest)) => !host.is_empty() && rest.split(['/', '?', '#']).next().unwrap_or("").eq_ignore_ascii_case(host),
+        // E.g. 'null' from a sandboxed page
+        None => false
     }
 }
 
 fn return_raw_file(request: &mut http::Request, file_name: &str, gallery_path: &String) -> core::Status {
+    let mut path = PathBuf::from(gallery_path);
+    path.push(file_name);
+
+    let validators = Validators::for_file(path.as_path());
+    if let Some(v) = &validators {
+        if let Some(status) = return_if_not_modified(request, v) {
+            return status;
+        }
+    }
+
     let mut buffer = NginxBuffer {
         request: request,
         first_chain: std::ptr::null_mut(),
         last_chain: std::ptr::null_mut()
     };
-    let mut path = PathBuf::from(gallery_path);
-    path.push(file_name);
     load_file(path.as_path(), &mut buffer); 
 
-    respond(&mut buffer, get_content_type(file_name))
+    respond(&mut buffer, get_content_type(file_name), validators.as_ref())
 }
 
-fn respond(buffer: &mut NginxBuffer, content_type: &str) -> core::Status {
+// Sets the cookie CSRF tokens are signed with, if the browser doesn't have one.
+fn add_crumb(request: &mut http::Request) {
+    if get_crumb(request).is_empty() {
+        let attributes = cookie_attributes(request, "Strict");
+        request.add_header_out("Set-Cookie", format!("crumb={}; {}", Uuid::new_v4(), attributes).as_str());
+    }
+}
+
+fn respond(buffer: &mut NginxBuffer, content_type: &str, validators: Option<&Validators>) -> core::Status {
     unsafe {
         (*(*buffer.last_chain).buf).set_last_buf(1);
     }
 
     buffer.request.set_status(HTTPStatus::OK);
     buffer.request.add_header_out("Content-Type", content_type);
-    
-    let crumb = get_crumb(buffer.request);
-    if crumb.is_empty() {
-        buffer.request.add_header_out("Set-Cookie", format!("crumb={}; HttpOnly", Uuid::new_v4()).as_str());
+    if let Some(v) = validators {
+        add_validator_headers(buffer.request, v);
     }
+    add_crumb(buffer.request);
 
     buffer.request.send_header();
 
@@ -397,43 +1285,202 @@ fn respond(buffer: &mut NginxBuffer, content_type: &str) -> core::Status {
     }
 }
 
-// Resizes the jpg to fit the screen
-fn return_jpg(request: &mut http::Request, query_string: Option<&str>, file_name: &str, uri_path: &str, gallery_path: &String) -> core::Status {
-    let start = Instant::now();
+// Work done on the nginx thread pool, so that e.g. a large original doesn't hold up other requests.
+trait PoolTask {
+    // Runs on a thread pool thread; mustn't touch the request or its pool.
+    fn run(&mut self);
 
-    let photo_id = match get_id(&file_name) {
-        Ok(id) => id,
-        Err(_) => { return core::Status::NGX_DECLINED; }
+    // Runs on the event loop once run() has finished.
+    fn respond(self, request: &mut http::Request) -> core::Status;
+}
+
+struct PostedTask<T> {
+    request: *mut ngx_http_request_t,
+    task: T
+}
+
+unsafe extern "C" fn task_thread_handler<T: PoolTask>(data: *mut c_void, _log: *mut ngx_log_t) {
+    (*(data as *mut PostedTask<T>)).task.run();
+}
+
+unsafe extern "C" fn task_event_handler<T: PoolTask>(ev: *mut ngx_event_t) {
+    let posted = (*ev).data as *mut PostedTask<T>;
+    let r = (*posted).request;
+    // The memory belongs to the request's pool, so the task is just moved out
+    let task = std::ptr::read(&(*posted).task);
+
+    let request = http::Request::from_ngx_http_request(r);
+    let rc = task.respond(request);
+
+    let c = (*r).connection;
+    ngx_http_finalize_request(r, rc.into());
+    ngx_http_run_posted_requests(c);
+}
+
+// Runs the task on the thread pool, or on the event loop if there's no thread pool.
+fn run_task<T: PoolTask>(request: &mut http::Request, thread_pool: Option<*mut ngx_thread_pool_t>, mut task: T) -> core::Status {
+    let tp = match thread_pool {
+        Some(tp) => tp,
+        None => {
+            task.run();
+            return task.respond(request);
+        }
     };
 
-    let query = parse_query_string(
-        match query_string {
-            Some(qs) => qs,
-            None => {
-                // Return the full size image if there's no size parameters to resize to.
-                let raw_jpg = get_filename_from_id(&gallery_path, photo_id, FileType::JPG);
+    unsafe {
+        let t = ngx_thread_task_alloc(request.pool().as_ptr(), std::mem::size_of::<PostedTask<T>>());
+        if t.is_null() {
+            return core::Status::NGX_ERROR;
+        }
 
-                return request.internal_redirect(get_raw_uri(uri_path, &raw_jpg).as_str());
-            }            
-        });
+        let ctx = (*t).ctx as *mut PostedTask<T>;
+        std::ptr::write(ctx, PostedTask { request: (&mut *request).into(), task });
+
+        (*t).handler = Some(task_thread_handler::<T>);
+        (*t).event.handler = Some(task_event_handler::<T>);
+        (*t).event.data = ctx as *mut c_void;
+
+        if ngx_thread_task_post(tp, t) != core::Status::NGX_OK.into() {
+            std::ptr::drop_in_place(ctx);
+            return core::Status::NGX_ERROR;
+        }
+
+        // Keep the request alive until task_event_handler finalizes it.
+        let main = (*(*ctx).request).main;
+        (*main).set_count((*main).count() + 1);
+    }
+
+    core::Status::NGX_DONE
+}
+
+// State for an image resize done on the nginx thread pool.
+struct ResizeTask {
+    path: PathBuf,
+    gallery_path: String,
+    rendition: Rendition,
+    cache: Option<RenditionCache>,
+    image: Vec<u8>,
+    start: Instant
+}
+
+impl PoolTask for ResizeTask {
+    // Fills in 'image' from the cache, or by resizing the original.
+    fn run(&mut self) {
+        if let Some(cache) = &self.cache {
+            if let Some(image) = cache.get(&self.gallery_path, &self.rendition) {
+                self.image = image;
+                return;
+            }
+        }
+
+        let path = self.path.as_path();
+        let rendition = &self.rendition;
+        let image = &mut self.image;
+        if catch_unwind(AssertUnwindSafe(|| resize_image(path, rendition.width, rendition.height, &rendition.options, image))).is_err() {
+            eprintln!("Resizing {} failed", path.display());
+            self.image.clear();
+            return;
+        }
+
+        if let Some(cache) = &self.cache {
+            cache.put(&self.gallery_path, &self.rendition, self.image.as_slice());
+        }
+    }
+
+    fn respond(self, request: &mut http::Request) -> core::Status {
+        ngx_log_debug_http!(request, "rust gallery image resize duration: {:?}", self.start.elapsed());
+        respond_resized(request, self.image, &self.rendition)
+    }
+}
+
+fn rendition_validators(rendition: &Rendition) -> Validators {
+    Validators::new(&rendition.tag(), rendition.mtime)
+}
+
+fn respond_resized(request: &mut http::Request, image: Vec<u8>, rendition: &Rendition) -> core::Status {
+    if image.is_empty() {
+        return return_value_with_status(request, "Unable to resize image", "text/plain", HTTPStatus::INTERNAL_SERVER_ERROR);
+    }
 
-    let file_path = get_file_path(&gallery_path, photo_id, FileType::JPG);
-    
     let mut buffer = NginxBuffer {
         request: request,
         first_chain: std::ptr::null_mut(),
         last_chain: std::ptr::null_mut()
     };
-    
-    resize_image(file_path.as_path(), 
-                    query.get("w").expect("No width in uri").parse::<u32>().expect("Bad image width"),
-                    query.get("h").expect("No height in uri").parse::<u32>().expect("Bad image height"),
-                    &mut buffer);
+    let _ = buffer.write_all(image.as_slice());
+    respond(&mut buffer, rendition.options.format.content_type(), Some(&rendition_validators(rendition)))
+}
+
+// Resizes the jpg to fit the screen
+fn return_jpg(request: &mut http::Request, query_string: Option<&str>, file_name: &str, uri_path: &str, gallery_path: &String) -> core::Status {
+    let photo_id = match get_id(&file_name) {
+        Ok(id) => id,
+        Err(_) => { return core::Status::NGX_DECLINED; }
+    };
+    let source = match get_filename_from_id(&gallery_path, photo_id, FileType::JPG) {
+        Some(s) => s,
+        None => { return return_value_with_status(request, "404 Not found", "text/html", HTTPStatus::NOT_FOUND); }
+    };
+
+    let co = Module::location_conf(request).expect("Module config exists");
+
+    // A share link has a query string, but no size
+    let query = parse_query_string(query_string.unwrap_or(""));
+    let (width, height) = match (query.get("w"), query.get("h")) {
+        (Some(w), Some(h)) => match (w.parse::<u32>(), h.parse::<u32>()) {
+            (Ok(w), Ok(h)) => (snap_to_size(w, &co.sizes), snap_to_size(h, &co.sizes)),
+            _ => { return return_value_with_status(request, "The width and height must be numbers", "text/plain", HTTPStatus::BAD_REQUEST); }
+        },
+        _ => {
+            // Return the full size image if there's no size parameters to resize to.
+            let browser_safe = MediaKind::from_path(&source).map(|k| k.is_browser_safe()).unwrap_or(true);
+            if browser_safe {
+                return redirect(request, get_raw_uri(uri_path, &source).as_str());
+            }
+
+            // e.g. HEIC, which has to be converted at full size
+            (u32::MAX, u32::MAX)
+        }
+    };
+
+    let cache = if co.cache_dir.is_empty() {
+        None
+    } else {
+        Some(RenditionCache { dir: PathBuf::from(&co.cache_dir), max_size: co.cache_max_size })
+    };
+
+    let mut options = co.resize_options();
+    if !co.formats.is_empty() {
+        options.format = negotiate_format(get_header(request, "Accept"), &co.formats);
+        request.add_header_out("Vary", "Accept");
+    }
 
-    let result = respond(&mut buffer, "image/jpeg");
-    ngx_log_debug_http!(request, "rust gallery image resize duration: {:?}", start.elapsed());
+    let path = Path::new(gallery_path).join(&source);
+    let (size, mtime) = source_stat(path.as_path());
+    let rendition = Rendition {
+        source: source,
+        width: width,
+        height: height,
+        options: options,
+        size: size,
+        mtime: mtime
+    };
+
+    // Don't decode anything if the browser already has it.
+    if let Some(status) = return_if_not_modified(request, &rendition_validators(&rendition)) {
+        return status;
+    }
+
+    let task = ResizeTask {
+        path: path,
+        gallery_path: gallery_path.clone(),
+        rendition: rendition,
+        cache: cache,
+        image: Vec::new(),
+        start: Instant::now()
+    };
 
-    return result;
+    run_task(request, co.thread_pool, task)
 }
 
 // Get uri to the raw file, not the 'id' file
@@ -445,6 +1492,58 @@ fn get_raw_uri(uri_path: &str, file_name: &String) -> String {
     uri
 }
 
+// A request this module redirected internally, e.g. to a photo's original, after checking it could
+// see it. nginx clears the request's ctx when it's redirected, so this is kept with the request's
+// pool, which lasts as long as the request.
+struct Redirect {
+    request: *const ngx_http_request_t,
+    uri: String
+}
+
+unsafe extern "C" fn drop_redirect(data: *mut c_void) {
+    std::ptr::drop_in_place(data as *mut Redirect);
+}
+
+fn redirect(request: &mut http::Request, uri: &str) -> core::Status {
+    unsafe {
+        let cln = ngx_pool_cleanup_add(request.pool().as_ptr(), std::mem::size_of::<Redirect>());
+        if cln.is_null() {
+            return core::Status::NGX_ERROR;
+        }
+
+        let r: *const ngx_http_request_t = (&*request).into();
+        std::ptr::write((*cln).data as *mut Redirect, Redirect { request: r, uri: uri.to_string() });
+        (*cln).handler = Some(drop_redirect);
+    }
+
+    request.internal_redirect(uri)
+}
+
+// Whether this is the request, and uri, that redirect() sent it to. Others that are internal, e.g.
+// from error_page, try_files or X-Accel-Redirect, are checked like any other.
+fn is_own_redirect(request: &http::Request) -> bool {
+    if request.as_ref().internal() == 0 {
+        return false;
+    }
+
+    let r: *const ngx_http_request_t = request.into();
+    let path = request.path().to_str().unwrap_or("");
+    unsafe {
+        let mut cln = (*request.as_ref().pool).cleanup;
+        while !cln.is_null() {
+            if (*cln).handler.map(|h| h as usize) == Some(drop_redirect as usize) {
+                let redirect = &*((*cln).data as *const Redirect);
+                if redirect.request == r && redirect.uri == path {
+                    return true;
+                }
+            }
+            cln = (*cln).next;
+        }
+    }
+
+    false
+}
+
 fn return_mp4(request: &mut http::Request, file_name: &str, uri_path: &str, gallery_path: &String) -> core::Status {
     if file_name.ends_with(".scaled.mp4") {
         return core::Status::NGX_DECLINED;
@@ -456,9 +1555,60 @@ fn return_mp4(request: &mut http::Request, file_name: &str, uri_path: &str, gall
     };
 
 
-    let mp4name = get_filename_from_id(&gallery_path, video_id, FileType::MP4);
+    let mp4name = match get_filename_from_id(&gallery_path, video_id, FileType::MP4) {
+        Some(n) => n,
+        None => { return return_value_with_status(request, "404 Not found", "text/html", HTTPStatus::NOT_FOUND); }
+    };
+
+    return redirect(request, get_raw_uri(uri_path, &mp4name).as_str());
+}
+
+// Loads the metadata of the gallery into IMAGES, if it isn't already or the file has changed.
+fn load_gallery(gallery_path: &String) -> std::io::Result<()> {
+    let path = get_metadata_file(gallery_path);
+    // Before reading, so a change while it's read is picked up next time
+    let validators = Validators::for_file(path.as_path());
+    if let Some(loaded) = IMAGES.read().unwrap().get(gallery_path) {
+        if loaded.validators.as_ref().map(|v| &v.etag) == validators.as_ref().map(|v| &v.etag) {
+            return Ok(());
+        }
+    }
+
+    let md = load_metadata(path.as_path())?;
+    let mut map = IMAGES.write().unwrap();
+    (*map).insert(gallery_path.clone(), LoadedGallery { gallery: md, validators });
+
+    Ok(())
+}
+
+// Lists the galleries beneath a directory that isn't itself a gallery.
+// Walking the directories and reading the galleries' metadata is done on the thread pool.
+struct IndexTask {
+    dir_path: String,
+    html: String
+}
+
+impl PoolTask for IndexTask {
+    fn run(&mut self) {
+        let mut summaries = Vec::new();
+        for relative in find_galleries(Path::new(&self.dir_path)) {
+            match summarize(Path::new(&self.dir_path), &relative) {
+                Ok(s) => summaries.push(s),
+                Err(e) => eprintln!("Leaving {}/{} out of the index as its metadata can't be read: {}", self.dir_path, relative.display(), e)
+            }
+        }
+
+        self.html = render_index("Galleries", summaries);
+    }
+
+    fn respond(self, request: &mut http::Request) -> core::Status {
+        return_value(request, self.html.as_str(), "text/html")
+    }
+}
 
-    return request.internal_redirect(get_raw_uri(uri_path, &mp4name).as_str());
+fn return_index(request: &mut http::Request, dir_path: &String) -> core::Status {
+    let co = Module::location_conf(request).expect("Module config exists");
+    run_task(request, co.thread_pool, IndexTask { dir_path: dir_path.clone(), html: String::new() })
 }
 
 fn get_metadata_file(path: &String) -> PathBuf {
@@ -468,61 +1618,332 @@ fn get_metadata_file(path: &String) -> PathBuf {
     return r;
 }
 
-// Return 'edit_caption.js if the client is 'localhost'.
+// The edit rules decide, if the client matches one. Otherwise editors are signed in, or on this
+// machine unless that's turned off.
+fn can_edit(request: &http::Request) -> bool {
+    let co = Module::location_conf(request).expect("Module config exists");
+    if let Some(allowed) = check_client(request, &co.edit_rules, &co.trusted_proxies, co.real_ip_header.unwrap_or_default()) {
+        return allowed;
+    }
+    get_editor(request, co).is_some() || (co.edit_from_localhost.unwrap_or(true) && is_localhost(request, &co.trusted_proxies, co.real_ip_header.unwrap_or_default()))
+}
+
+// Return 'edit_caption.js if the client can edit captions.
 fn return_edit_caption(request: &mut http::Request, gallery_path: &String) -> core::Status {
-    let rv = if is_localhost(request) {
+    // The page that loads it sets the crumb
+    let rv = if can_edit(request) && !get_crumb(request).is_empty() {
         let mut js_path = PathBuf::from(gallery_path);
         js_path.push("edit_caption.js");
     
         let js = read_to_string(js_path.as_path()).expect("edit_caption.js doesn't exist");
-        format!("const crumb = \"{}\";\n\n{}", get_crumb(request), js)
+        format!("const csrfToken = \"{}\";\n\n{}", csrf_token(get_crumb(request), get_session(request)), js)
     } else {
         String::from("{}")
     };
     return_value(request, rv.as_str(), "application/javascript")
 }
 
-fn handle_caption(request: &mut http::Request, query_string: Option<&str>, id_str: &str, gallery_path: &String) -> core::Status {
-    if !is_localhost(request) {
-        eprintln!("Attempt to edit a caption without being localhost");
+// Checks the client can edit captions before reading the new one from the body.
+fn handle_caption(request: &mut http::Request) -> core::Status {
+    if !can_edit(request) {
+        eprintln!("Attempt to edit a caption without being an editor");
+        request.discard_request_body();
         return return_value_with_status(request, "Not permitted", "text/plain", HTTPStatus::UNAUTHORIZED);
     }
-    let id = id_str.parse::<usize>().expect("Failure parsing an id") - 1;
 
-    let query = parse_query_string(query_string.expect("Badly formed query string"));
-    let crumb = match query.get("crumb") {
-        Some(c) => c,
-        None => {
-            eprintln!("Attempt to edit a caption with no crumb. CSRF attack?");
-            return return_value_with_status(request, "Not permitted", "text/plain", HTTPStatus::UNAUTHORIZED);
+    let token = get_header(request, "X-CSRF-Token").unwrap_or("");
+    if !is_valid_csrf_token(get_crumb(request), get_session(request), token) {
+        eprintln!("Attempt to edit a caption without the correct CSRF token. CSRF attack?");
+        request.discard_request_body();
+        return return_value_with_status(request, "Not permitted", "text/plain", HTTPStatus::UNAUTHORIZED);
+    }
+
+    read_body(request, caption_body_handler)
+}
+
+unsafe extern "C" fn caption_body_handler(r: *mut ngx_http_request_t) {
+    let body = get_body(r);
+    let request = http::Request::from_ngx_http_request(r);
+    let rc = save_caption(request, body);
+    ngx_http_finalize_request(r, rc.into());
+}
+
+fn save_caption(request: &mut http::Request, body: Option<Vec<u8>>) -> core::Status {
+    let co = Module::location_conf(request).expect("Module config exists");
+    let max_length = if co.caption_max_length == 0 { DEFAULT_MAX_LENGTH } else { co.caption_max_length };
+    let body = match body {
+        Some(b) => b,
+        None => { return return_value_with_status(request, "The caption can't be read", "text/plain", HTTPStatus::INTERNAL_SERVER_ERROR); }
+    };
+    let caption = match parse_caption(get_header(request, "Content-Type").unwrap_or(""), &body, max_length) {
+        Ok(c) => c,
+        Err(CaptionError::Invalid(e)) => { return return_value_with_status(request, e, "text/plain", HTTPStatus::BAD_REQUEST); },
+        Err(CaptionError::TooLong(max)) => {
+            let e = format!("Captions can be at most {} characters", max);
+            return return_value_with_status(request, e.as_str(), "text/plain", HTTPStatus::REQUEST_ENTITY_TOO_LARGE);
         }
     };
-    if *crumb != get_crumb(request) {
-        eprintln!("Attempt to edit a caption without the correct crumb. CSRF attack?");
-        return return_value_with_status(request, "Not permitted", "text/plain", HTTPStatus::UNAUTHORIZED);
+
+    // The gallery and id are in the path, e.g. '/gallery/iceland/12'
+    let path = String::from(request.path().to_str().expect("Path not UTF8"));
+    let (uri_path, id_str) = path.rsplit_once('/').expect("Caption path has a /");
+    let gallery_path = format!("{}{}", co.root, uri_path);
+    let id = match id_str.parse::<usize>().ok().and_then(|i| i.checked_sub(1)) {
+        Some(i) => i,
+        None => { return return_value_with_status(request, "404 Not found", "text/html", HTTPStatus::NOT_FOUND); }
+    };
+
+    run_task(request, co.thread_pool, CaptionTask { gallery_path, id, caption, status: HTTPStatus::OK, message: String::new() })
+}
+
+// Saving a caption locks, writes and syncs the metadata, so it's done on the thread pool.
+struct CaptionTask {
+    gallery_path: String,
+    id: usize,
+    caption: String,
+    status: HTTPStatus,
+    message: String
+}
+
+impl PoolTask for CaptionTask {
+    fn run(&mut self) {
+        // Only photos and videos that are in a gallery have a caption
+        match load_gallery(&self.gallery_path) {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.status = HTTPStatus::NOT_FOUND;
+                return;
+            },
+            Err(e) => {
+                eprintln!("Unable to load {} with error {}", get_metadata_file(&self.gallery_path).display(), e);
+                self.status = HTTPStatus::INTERNAL_SERVER_ERROR;
+                self.message = String::from("The gallery metadata can't be read");
+                return;
+            }
+        }
+        if !IMAGES.read().unwrap().get(&self.gallery_path).map(|l| self.id < l.gallery.items.len()).unwrap_or(false) {
+            self.status = HTTPStatus::NOT_FOUND;
+            return;
+        }
+
+        self.message = String::from("Ok");
+        if let Err(e) = update_caption(get_metadata_file(&self.gallery_path).as_path(), self.id, &self.caption) {
+            self.message = format!("Failed to write with error: {}", e.to_string());
+            self.status = HTTPStatus::INTERNAL_SERVER_ERROR;
+        }
     }
-    let caption = match query.get("caption") {
-        Some(c) => c,
-        None => {
-            return return_value_with_status(request, "Not permitted", "text/plain", HTTPStatus::UNAUTHORIZED);
+
+    // On the event loop, so the gallery isn't unloaded while a request is between loading and using it
+    fn respond(self, request: &mut http::Request) -> core::Status {
+        if self.status == HTTPStatus::NOT_FOUND {
+            return return_value_with_status(request, "404 Not found", "text/html", HTTPStatus::NOT_FOUND);
+        }
+
+        // Will cause a lazy load of metadata on the next request, to pick up the new caption
+        (*IMAGES.write().unwrap()).remove(&self.gallery_path);
+
+        // Need to respond with something.
+        return_value_with_status(request, self.message.as_str(), "text/plain", self.status)
+    }
+}
+
+// The path as the browser sent it, e.g. for a cookie's Path, rather than decoded
+fn get_encoded_path(request: &http::Request) -> String {
+    let uri = request.unparsed_uri().to_str().unwrap_or("/");
+    uri.split('?').next().unwrap_or("/").to_string()
+}
+
+// The users of the valid sessions, which is empty for viewers
+fn get_session_users<'a>(request: &'a http::Request, co: &ModuleConfig) -> Vec<&'a str> {
+    if !co.has_login() {
+        return Vec::new();
+    }
+
+    get_cookies(request, SESSION_COOKIE).into_iter().filter_map(|s| session_user(|u| co.session_key(u), s, now())).collect()
+}
+
+fn is_signed_in(request: &http::Request, co: &ModuleConfig) -> bool {
+    !get_session_users(request, co).is_empty()
+}
+
+// An editor that's still in the editors file
+fn get_editor<'a>(request: &'a http::Request, co: &ModuleConfig) -> Option<&'a str> {
+    get_session_users(request, co).into_iter().find(|u| co.editors.iter().any(|e| e.0 == *u))
+}
+
+// A gallery share link sets a cookie, '<expiry>.<signature>', for the gallery's path so the page
+// can load its photos. Albums are beneath it, so any directory above the request may be shared.
+fn has_shared_gallery(request: &http::Request, co: &ModuleConfig) -> bool {
+    if co.share_secret.is_empty() {
+        return false;
+    }
+
+    let path = request.path().to_str().unwrap_or("");
+    is_shared(&co.share_secret, path, &get_cookies(request, SHARE_COOKIE), now())
+}
+
+// Returns the response if the request isn't allowed to see the location, before anything's read.
+fn check_access(request: &mut http::Request, co: &ModuleConfig, query_string: Option<&str>, file_name: &str) -> Option<core::Status> {
+    let query = parse_query_string(query_string.unwrap_or(""));
+    if let (Some(expires), Some(signature)) = (query.get("exp"), query.get("sig")) {
+        let path = request.path().to_str().unwrap_or("").to_string();
+        if co.share_secret.is_empty() || !is_valid_share(&co.share_secret, &path, expires, signature, now()) {
+            eprintln!("Expired or invalid share link for {}", path);
+            request.discard_request_body();
+            return Some(return_value_with_status(request, "This link has expired or isn't valid", "text/plain", HTTPStatus::FORBIDDEN));
         }
+
+        // A gallery rather than a photo, so let the page load the rest of it
+        if file_name.is_empty() {
+            let max_age = expires.parse::<u64>().unwrap_or(0).saturating_sub(now());
+            let attributes = cookie_attributes(request, "Lax");
+            request.add_header_out("Set-Cookie", format!("{}={}.{}; Path={}; Max-Age={}; {}",
+                                                         SHARE_COOKIE, expires, signature, get_encoded_path(request), max_age, attributes).as_str());
+        }
+        return None;
+    }
+
+    // Signing in is handled with everything else
+    if is_signed_in(request, co) || has_shared_gallery(request, co) || (file_name == "login" && co.has_login()) {
+        return None;
+    }
+
+    if co.passwords.is_empty() {
+        request.discard_request_body();
+        return Some(return_value_with_status(request, "Not permitted", "text/plain", HTTPStatus::FORBIDDEN));
+    }
+    request.discard_request_body();
+    if file_name.is_empty() || file_name == "index.html" {
+        return Some(return_login(request, "", HTTPStatus::UNAUTHORIZED));
+    }
+    Some(return_value_with_status(request, "Not signed in", "text/plain", HTTPStatus::UNAUTHORIZED))
+}
+
+fn return_login(request: &mut http::Request, error: &str, status: HTTPStatus) -> core::Status {
+    let co = Module::location_conf(request).expect("Module config exists");
+    let error = error.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    // Only editors have a name
+    let name = if co.editors.is_empty() {
+        ""
+    } else {
+        "<input type=\"text\" name=\"name\" placeholder=\"Name, if editing\" autocomplete=\"username\" />"
     };
+    let page = LOGIN_TEMPLATE.replace("{{error}}", &error).replace("{{name}}", name);
+    return_value_with_status(request, page.as_str(), "text/html", status)
+}
 
-    let mut rv = String::from("Ok");
-    let mut status = HTTPStatus::OK;
-    match update_caption(get_metadata_file(gallery_path).as_path(), id, &format!("{}", urlencoding::decode(caption).unwrap())) {
-        Ok(_) => (),
-        Err(e) => {
-            rv = format!("Failed to write with error: {}", e.to_string());
-            status = HTTPStatus::UNAUTHORIZED;
+// Reads the request body, which arrives asynchronously, and then calls 'handler', which must
+// finalize the request.
+fn read_body(request: &mut http::Request, handler: unsafe extern "C" fn(*mut ngx_http_request_t)) -> core::Status {
+    let rc = unsafe { ngx_http_read_client_request_body(request.into(), Some(handler)) };
+    if rc >= NGX_HTTP_SPECIAL_RESPONSE as ngx_int_t {
+        return core::Status(rc);
+    }
+
+    core::Status::NGX_DONE
+}
+
+// The body read by read_body. nginx keeps bodies larger than client_body_buffer_size in a
+// temporary file, which is read back; client_max_body_size limits how large they can be. None if
+// the file can't be read.
+unsafe fn get_body(r: *mut ngx_http_request_t) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    if (*r).request_body.is_null() {
+        return Some(body);
+    }
+
+    let mut cl = (*(*r).request_body).bufs;
+    while !cl.is_null() {
+        let b = (*cl).buf;
+        if (*b).in_file() != 0 {
+            let start = body.len();
+            body.resize(start + ((*b).file_last - (*b).file_pos) as usize, 0);
+            let mut read = start;
+            while read < body.len() {
+                let n = libc::pread((*(*b).file).fd, body[read..].as_mut_ptr() as *mut c_void, body.len() - read,
+                                    (*b).file_pos + (read - start) as i64);
+                if n <= 0 {
+                    eprintln!("Unable to read the request body from {}: {}",
+                              (*(*b).file).name.to_string(), std::io::Error::last_os_error());
+                    return None;
+                }
+                read += n as usize;
+            }
+        } else {
+            body.extend_from_slice(std::slice::from_raw_parts((*b).pos, (*b).last.offset_from((*b).pos) as usize));
+        }
+        cl = (*cl).next;
+    }
+
+    Some(body)
+}
+
+unsafe extern "C" fn login_body_handler(r: *mut ngx_http_request_t) {
+    let body = get_body(r);
+    let request = http::Request::from_ngx_http_request(r);
+    let rc = handle_login(request, body);
+    ngx_http_finalize_request(r, rc.into());
+}
+
+// Checking a password is deliberately slow, so it's done on the thread pool.
+struct LoginTask {
+    co: &'static ModuleConfig,
+    name: String,
+    password: String,
+    valid: bool
+}
+
+impl PoolTask for LoginTask {
+    // Viewers just have a password, and editors a name too
+    fn run(&mut self) {
+        self.valid = if self.name.is_empty() {
+            self.co.passwords.iter().any(|p| p.verify(&self.password))
+        } else {
+            // Always check one hash, so the time taken doesn't show which names are editors
+            let editor = self.co.editors.iter().find(|e| e.0 == self.name);
+            let valid = editor.map(|e| &e.1).unwrap_or(&DUMMY_HASH).verify(&self.password);
+            editor.is_some() && valid
+        };
+    }
+
+    fn respond(self, request: &mut http::Request) -> core::Status {
+        if !self.valid {
+            eprintln!("Failed login to {}", request.path().to_str().unwrap_or(""));
+            return return_login(request, "The name or password is incorrect", HTTPStatus::UNAUTHORIZED);
         }
+
+        let key = self.co.session_key(&self.name).expect("A valid login has a session key");
+        let session = make_session(&key, now() + SESSION_DURATION, &self.name);
+        let attributes = cookie_attributes(request, "Lax");
+        request.add_header_out("Set-Cookie", format!("{}={}; Path={}; Max-Age={}; {}",
+                                                     SESSION_COOKIE, session, self.co.session_path, SESSION_DURATION, attributes).as_str());
+
+        // Back to the gallery, or index, that the form was on
+        let path = get_encoded_path(request);
+        let page = path.strip_suffix("login").unwrap_or(&path).to_string();
+        request.add_header_out("Location", page.as_str());
+        request.set_status(HTTPStatus::SEE_OTHER);
+        request.as_mut().set_header_only(1);
+        request.send_header()
     }
+}
 
-    // Will cause a lazy load of metadata on the next request, to pick up the new caption
-    (*IMAGES.write().unwrap()).remove(gallery_path);
+// Checks the password posted by the login form and, if it's right, sets the session cookie
+// and redirects back to the page.
+fn handle_login(request: &mut http::Request, body: Option<Vec<u8>>) -> core::Status {
+    let body = match body {
+        Some(b) => b,
+        None => { return return_value_with_status(request, "The form can't be read", "text/plain", HTTPStatus::INTERNAL_SERVER_ERROR); }
+    };
+    let form = parse_query_string(&String::from_utf8_lossy(&body));
+    let get = |name: &str| form.get(name)
+                               .and_then(|p| urlencoding::decode(&p.replace('+', " ")).ok().map(|p| p.into_owned()))
+                               .unwrap_or_default();
+    let name = get("name").trim().to_string();
+    let password = get("password");
 
-    // Need to respond with something.
-    return_value_with_status(request, rv.as_str(), "text/plain", status)
+    let co = Module::location_conf(request).expect("Module config exists");
+    run_task(request, co.thread_pool, LoginTask { co, name, password, valid: false })
 }
 
 // Implement a request handler. The convenience macro (http_request_handler!) will
@@ -537,8 +1958,6 @@ http_request_handler!(rust_gallery_access_handler, |request: &mut http::Request|
         return core::Status::NGX_DECLINED;
     }
 
-    request.discard_request_body();
-
     let uri = request.unparsed_uri().to_str().expect("Uri not UTF8").parse::<Uri>().expect("Unable to parse uri");
     let uri_path = String::from(request.path().to_str().expect("Path not UTF8"));
     let query_string = uri.query();
@@ -547,19 +1966,64 @@ http_request_handler!(rust_gallery_access_handler, |request: &mut http::Request|
         None => { return core::Status::NGX_DECLINED }
     };
 
+    // Everything, including the original files, is checked. Our own redirects, e.g. to the
+    // original of a photo, were checked before they were redirected. Clients the rules allow
+    // don't need a password or share link.
+    if !is_own_redirect(request) {
+        match check_client(request, &co.access_rules, &co.trusted_proxies, co.real_ip_header.unwrap_or_default()) {
+            Some(true) => (),
+            Some(false) => {
+                request.discard_request_body();
+                return return_value_with_status(request, "Not permitted", "text/plain", HTTPStatus::FORBIDDEN);
+            },
+            None if co.is_private() => {
+                if let Some(status) = check_access(request, co, query_string, file_name) {
+                    return status;
+                }
+            },
+            None => ()
+        }
+    }
+
+    // So another site's page can't sign in or edit captions
+    if request.method() == Method::POST && !is_same_origin(request) {
+        eprintln!("Cross-origin POST to {}", request.path().to_str().unwrap_or(""));
+        request.discard_request_body();
+        return return_value_with_status(request, "Not permitted", "text/plain", HTTPStatus::FORBIDDEN);
+    }
+
+    if file_name == "login" && co.has_login() {
+        if request.method() == Method::POST {
+            return read_body(request, login_body_handler);
+        }
+        request.discard_request_body();
+        return return_login(request, "", HTTPStatus::OK);
+    }
+
+    // A new caption for the photo or video with the id, which is in the body
+    if request.method() == Method::POST && file_name.parse::<usize>().is_ok() {
+        return handle_caption(request);
+    }
+
+    request.discard_request_body();
+
     let gallery_path = format!("{}{}", root_path, uri_path); 
 
-    if IMAGES.read().unwrap().get(&gallery_path).is_none() {
-        let r = get_metadata_file(&gallery_path);
-        let md = match load_metadata(r.as_path()) {
-            Ok(m) => m,
-            Err(_) => {
-                // Should really send the 404 page configured for the nginx location
-                return return_value_with_status(request, "404 Not found", "text/html", HTTPStatus::NOT_FOUND);
+    match load_gallery(&gallery_path) {
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            // Not a gallery, but maybe a directory of them
+            if file_name.is_empty() && Path::new(&gallery_path).is_dir() {
+                return return_index(request, &gallery_path);
             }
-        };
-        let mut map = IMAGES.write().unwrap();
-        (*map).insert(gallery_path.clone(), md);
+            // Should really send the 404 page configured for the nginx location
+            return return_value_with_status(request, "404 Not found", "text/html", HTTPStatus::NOT_FOUND);
+        },
+        Err(e) => {
+            // E.g. written by a newer make-gallery
+            eprintln!("Unable to load {} with error {}", get_metadata_file(&gallery_path).display(), e);
+            return return_value_with_status(request, "The gallery metadata can't be read", "text/plain", HTTPStatus::INTERNAL_SERVER_ERROR);
+        }
     }
 
     ngx_log_debug_http!(request, "Rust Gallery handling: {}", file_name);
@@ -570,15 +2034,12 @@ http_request_handler!(rust_gallery_access_handler, |request: &mut http::Request|
         "edit_caption.js" => return_edit_caption(request, &gallery_path),
         _ => {
             let f_n = &file_name.to_string(); 
-            if is_jpg(f_n) {
+            if is_photo(f_n) {
                 return return_jpg(request, query_string, file_name, uri_path, &gallery_path);
             }
             if is_mp4(f_n) {
                 return return_mp4(request, file_name, uri_path, &gallery_path);
             }
-            if request.method() == Method::POST {
-                return handle_caption(request, query_string, file_name, &gallery_path);
-            }
             return_raw_file(request, "index.html", &gallery_path)
         }
     }
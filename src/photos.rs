@@ -1,34 +1,92 @@
 use std::cmp::min;
+use std::collections::HashMap;
 use std::fs;
+use std::fs::File;
 
-use std::io::Cursor;
+use std::io::BufReader;
+use std::io::BufWriter;
 use std::io::Write;
 
-use std::path::Path;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+use std::path::{ Path, PathBuf };
 
 use std::process::Command;
 
-use std::slice;
+use std::sync::Mutex;
 
 use chrono::NaiveDateTime;
 
 use serde::{ Deserialize, Serialize };
 
+use exif::{ In, Reader, Tag };
+
+#[cfg(feature = "heic")]
+use libheif_rs::{ ColorSpace, HeifContext, HeifError, LibHeif, RgbChroma };
+
+#[cfg(feature = "avif")]
+use image::codecs::avif::AvifEncoder;
 use image::codecs::jpeg::JpegEncoder;
 use image::io::Reader as ImageReader;
 use image::ImageError;
 use image::DynamicImage;
+use image::error::ImageFormatHint;
+#[cfg(feature = "heic")]
+use image::error::DecodingError;
+#[cfg(not(feature = "heic"))]
+use image::error::{ UnsupportedError, UnsupportedErrorKind };
 use image::GenericImage;
 use image::imageops::FilterType;
 use image::imageops::resize;
 use image::imageops::thumbnail;
 use image::ImageBuffer;
+use image::ImageFormat;
 use image::ImageResult;
 use image::Rgba;
+use image::RgbaImage;
+
+use rayon::prelude::*;
+
+use uuid::Uuid;
 
 pub static MD_FILE : &str = "metadata";
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Jpeg,
+    Png,
+    WebP,
+    Tiff,
+    Heic,
+    Video
+}
+
+impl MediaKind {
+    pub fn from_path(path: &String) -> Option<MediaKind> {
+        let ext = Path::new(path).extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "jpg" | "jpeg" => Some(MediaKind::Jpeg),
+            "png"          => Some(MediaKind::Png),
+            "webp"         => Some(MediaKind::WebP),
+            "tif" | "tiff" => Some(MediaKind::Tiff),
+            "heic" | "heif" => Some(MediaKind::Heic),
+            "mp4" | "mov" | "avi" => Some(MediaKind::Video),
+            _ => None
+        }
+    }
+
+    // Can browsers display the original as is?
+    pub fn is_browser_safe(&self) -> bool {
+        match self {
+            MediaKind::Jpeg | MediaKind::Png | MediaKind::WebP | MediaKind::Video => true,
+            MediaKind::Tiff | MediaKind::Heic => false
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Image {
     pub path: String,
     pub caption: String,
@@ -36,28 +94,264 @@ pub struct Image {
     pub width: u16,
     pub height: u16,
     pub mp4_scaled: bool,
-    pub location: Option<String>
+    pub location: Option<String>,    // For display, e.g. 37 deg 46' 29.64" N, 122 deg 25' 9.84" W
+    #[serde(default)]
+    pub kind: Option<MediaKind>,    // Not in older metadata, so see kind()
+    #[serde(default)]
+    pub gps: Option<GpsPosition>,
+    #[serde(default)]
+    pub size: u64,    // Of the file, with mtime to tell if it has changed since the metadata was made
+    #[serde(default)]
+    pub mtime: u64    // Seconds since the epoch
+}
+
+// Decimal degrees; south and west are negative.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct GpsPosition {
+    pub latitude: f64,
+    pub longitude: f64
+}
+
+impl GpsPosition {
+    // In the format exiftool uses, which the front end parses into a map link.
+    pub fn to_location(&self) -> String {
+        format!("{}, {}", to_dms(self.latitude, 'N', 'S'), to_dms(self.longitude, 'E', 'W'))
+    }
+}
+
+fn to_dms(degrees: f64, positive: char, negative: char) -> String {
+    // Round to hundredths of a second up front so we never print 60 seconds
+    let hundredths = (degrees.abs() * 360000.0).round() as u64;
+    let d = hundredths / 360000;
+    let m = hundredths % 360000 / 6000;
+    let s = (hundredths % 6000) as f64 / 100.0;
+    let hemisphere = if degrees < 0.0 { negative } else { positive };
+
+    format!("{} deg {}' {:.2}\" {}", d, m, s, hemisphere)
 }
 
 impl Image {
     pub fn is_mp4(&self) -> bool {
         is_mp4(&self.path)
     }
+
+    pub fn kind(&self) -> Option<MediaKind> {
+        self.kind.or_else(|| MediaKind::from_path(&self.path))
+    }
 }
 
-pub fn load_metadata(path: &Path) -> std::io::Result<Vec<Image>> {
-    let mut buffer = Vec::<u8>::new();
-    load_file(path, &mut buffer);
-    let images = match serde_json::from_reader(Cursor::new(unsafe {
-        slice::from_raw_parts_mut(buffer.as_mut_ptr(), buffer.len())})) {
-            Ok(i) => i,
-            Err(e) => {
-                eprintln!("Error readng metadata, which is\n{}", std::str::from_utf8(&buffer).unwrap());
-                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
-            }
+// Version 1 was a bare array of items. Bump this, and add a migration to migrate(), when
+// the format changes in a way older versions can't read.
+pub const METADATA_VERSION: u32 = 2;
+
+// About the gallery as a whole, rather than an item in it. Set from gallery.toml, other
+// than the dates, which are those of the first and last items.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct GalleryInfo {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    pub cover: Option<usize>,    // Id of the photo, i.e. index + 1 as in '12.jpg'
+    pub start: Option<NaiveDateTime>,
+    pub end: Option<NaiveDateTime>,
+    pub albums: Vec<Album>,      // In the order they're shown
+    pub parent: Option<String>   // Title of the gallery this is an album of
+}
+
+// A subdirectory of the gallery with its own metadata, summarized so the gallery can list
+// its albums without loading them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Album {
+    pub path: String,            // The directory name
+    pub title: String,
+    pub cover: String,           // As returned by Gallery::cover(), relative to the album
+    pub count: usize,            // Including the album's own albums
+    pub start: Option<NaiveDateTime>,
+    pub end: Option<NaiveDateTime>
+}
+
+impl Album {
+    pub fn new(path: &str, album: &Gallery) -> Album {
+        Album {
+            path: path.to_string(),
+            title: album.gallery.title.clone().unwrap_or_else(|| path.to_string()),
+            cover: album.cover(),
+            count: album.count(),
+            start: album.gallery.start,
+            end: album.gallery.end
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Gallery {
+    pub version: u32,
+    #[serde(default)]
+    pub gallery: GalleryInfo,
+    pub items: Vec<Image>
+}
+
+impl Gallery {
+    pub fn new(gallery: GalleryInfo, items: Vec<Image>) -> Gallery {
+        Gallery { version: METADATA_VERSION, gallery, items }
+    }
+
+    // The photo shown for the gallery without the extension, relative to the gallery, e.g. '12',
+    // or 'Paris/3' when the photos are all in albums.
+    pub fn cover(&self) -> String {
+        match self.gallery.albums.first() {
+            Some(album) if self.items.is_empty() => format!("{}/{}", album.path, album.cover),
+            _ => self.gallery.cover.unwrap_or(1).to_string()
+        }
+    }
+
+    // The number of items, including those in albums.
+    pub fn count(&self) -> usize {
+        self.items.len() + self.gallery.albums.iter().map(|a| a.count).sum::<usize>()
+    }
+}
+
+fn invalid_metadata(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+// Upgrades metadata of an older version to the current one, a version at a time.
+fn migrate(mut value: serde_json::Value) -> std::io::Result<serde_json::Value> {
+    loop {
+        let version = match value {
+            serde_json::Value::Array(_) => 1,
+            _ => value.get("version")
+                      .and_then(|v| v.as_u64())
+                      .ok_or_else(|| invalid_metadata(String::from("Metadata has no version")))? as u32
         };
-    
-    Ok(images)
+
+        if version > METADATA_VERSION {
+            return Err(invalid_metadata(format!("Metadata is version {} but only versions up to {} are supported; upgrade rust_gallery",
+                                                version, METADATA_VERSION)));
+        }
+        if version == METADATA_VERSION {
+            return Ok(value);
+        }
+
+        value = match version {
+            1 => serde_json::json!({ "version": 2, "gallery": {}, "items": value }),
+            _ => return Err(invalid_metadata(format!("Metadata version {} is not supported", version)))
+        };
+    }
+}
+
+pub fn load_metadata(path: &Path) -> std::io::Result<Gallery> {
+    let buffer = fs::read(path)?;
+    let value = match serde_json::from_slice::<serde_json::Value>(&buffer) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Error readng metadata, which is\n{}", String::from_utf8_lossy(&buffer));
+            // Metadata is replaced whole, so this shouldn't happen, but the previous version is kept
+            let backup = backup_path(path);
+            if let Some(v) = fs::read(&backup).ok().and_then(|b| serde_json::from_slice::<serde_json::Value>(&b).ok()) {
+                eprintln!("Using the previous metadata, {}", backup.display());
+                return serde_json::from_value(migrate(v)?).map_err(|e| invalid_metadata(format!("Metadata {} is not valid: {}", backup.display(), e)));
+            }
+            return Err(invalid_metadata(e.to_string()));
+        }
+    };
+
+    serde_json::from_value(migrate(value)?).map_err(|e| invalid_metadata(format!("Metadata {} is not valid: {}", path.display(), e)))
+}
+
+// Holds an exclusive lock on a gallery's metadata until dropped. nginx workers and make-gallery
+// are separate processes, so it's an advisory lock on 'metadata.lock' beside it.
+pub struct MetadataLock {
+    _file: File
+}
+
+pub fn lock_metadata(path: &Path) -> std::io::Result<MetadataLock> {
+    // nginx's user and make-gallery's may differ, and whichever creates the file owns it, so it's
+    // opened read-only, which flock allows, unless it has to be created
+    let lock_path = with_suffix(path, "lock");
+    let file = match File::open(&lock_path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            match fs::OpenOptions::new().create(true).truncate(false).write(true).mode(0o666).open(&lock_path) {
+                Ok(f) => f,
+                // Made by the other user in the meantime
+                Err(_) => File::open(&lock_path)?
+            }
+        },
+        Err(e) => { return Err(e); }
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(MetadataLock { _file: file })
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+// The previous version of the metadata, e.g. 'metadata.bak'
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, "bak")
+}
+
+pub fn save_metadata(path: &Path, gallery: &Gallery) -> std::io::Result<()> {
+    let _lock = lock_metadata(path)?;
+    write_metadata(path, gallery)
+}
+
+// Saves metadata that was built from an earlier read of it, whose captions were 'read'. Captions
+// edited since, e.g. through nginx while make-gallery was running, are kept rather than undone.
+pub fn save_metadata_keeping_captions(path: &Path, gallery: &mut Gallery, read: &HashMap<String, String>) -> std::io::Result<()> {
+    let _lock = lock_metadata(path)?;
+    if let Ok(current) = load_metadata(path) {
+        let edited: HashMap<String, String> = current.items.into_iter()
+                                                           .filter(|i| read.get(&i.path) != Some(&i.caption))
+                                                           .map(|i| (i.path, i.caption))
+                                                           .collect();
+        for item in gallery.items.iter_mut() {
+            if let Some(caption) = edited.get(&item.path) {
+                item.caption = caption.clone();
+            }
+        }
+    }
+
+    write_metadata(path, gallery)
+}
+
+// Writes a new file and renames it over the old one, so readers see one or the other and a crash
+// can't leave it half written. The caller holds the lock.
+fn write_metadata(path: &Path, gallery: &Gallery) -> std::io::Result<()> {
+    let temp = with_suffix(path, &format!("{}.tmp", std::process::id()));
+    let result = (|| {
+        let mut file = File::create(&temp)?;
+        file.write_all(serde_json::to_string_pretty(gallery).unwrap().as_bytes())?;
+        file.sync_all()?;
+
+        // A link is instant and keeps the old file after the rename
+        if path.exists() {
+            let backup = backup_path(path);
+            let _ = fs::remove_file(&backup);
+            if fs::hard_link(path, &backup).is_err() {
+                fs::copy(path, &backup)?;
+            }
+        }
+
+        fs::rename(&temp, path)?;
+        // So the rename survives a crash too
+        let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        File::open(dir)?.sync_all()
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+
+    result
 }
 
 pub fn load_file(path: &Path, buffer: &mut dyn Write) {
@@ -71,9 +365,11 @@ pub fn load_file(path: &Path, buffer: &mut dyn Write) {
     }
 }
 
+// The lock is held from reading to writing, so edits in other workers aren't lost.
 pub fn update_caption(path: &Path, id: usize, caption: &String) -> std::io::Result<()> {
-    let mut images = load_metadata(&path).expect(format!("Metadata doesn't exist at {}", path.display()).as_str());
-    match images.get_mut(id) {
+    let _lock = lock_metadata(path)?;
+    let mut gallery = load_metadata(&path)?;
+    match gallery.items.get_mut(id) {
         Some(i) => {
             i.caption = caption.clone();
         },
@@ -81,7 +377,7 @@ pub fn update_caption(path: &Path, id: usize, caption: &String) -> std::io::Resu
             eprintln!("Can't write caption at {} to {}", id, path.display());
         }
     }
-    match fs::write(path.as_os_str(), serde_json::to_string_pretty(&images).unwrap()) {
+    match write_metadata(path, &gallery) {
         Ok(_) => Ok(()),
         Err(e) => {
             eprintln!("Unable to write {} with error {}", path.display(), e.to_string());
@@ -90,16 +386,167 @@ pub fn update_caption(path: &Path, id: usize, caption: &String) -> std::io::Resu
     }
 }
 
+// Decodes the image and rotates/flips it to the way it's meant to be displayed.
 pub fn read_image(path: &Path) -> ImageResult<DynamicImage> {
+    if is_heic(&path.to_string_lossy().to_string()) {
+        // libheif applies the orientation itself
+        return read_heic(path);
+    }
+
     let f = match ImageReader::open(&path)?.with_guessed_format() {
         Ok(v) => v,
         Err(e) => return Err(ImageError::IoError(e))
     };
-    f.decode() 
+    Ok(apply_orientation(f.decode()?, read_orientation(path)))
+}
+
+#[cfg(feature = "heic")]
+fn read_heic(path: &Path) -> ImageResult<DynamicImage> {
+    let err = |e: HeifError| ImageError::Decoding(DecodingError::new(ImageFormatHint::Name(String::from("HEIC")), e));
+
+    let ctx = HeifContext::read_from_file(&path.to_string_lossy()).map_err(err)?;
+    let handle = ctx.primary_image_handle().map_err(err)?;
+    let image = LibHeif::new().decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None).map_err(err)?;
+    let planes = image.planes();
+    let plane = planes.interleaved.expect("RGBA images are interleaved");
+
+    // Rows may be padded
+    let row_len = plane.width as usize * 4;
+    let mut pixels = Vec::<u8>::with_capacity(row_len * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..row_len]);
+    }
+
+    let buffer = ImageBuffer::<Rgba<u8>, Vec<u8>>::from_raw(plane.width, plane.height, pixels).expect("Buffer is large enough");
+    Ok(DynamicImage::ImageRgba8(buffer))
+}
+
+#[cfg(not(feature = "heic"))]
+fn read_heic(_path: &Path) -> ImageResult<DynamicImage> {
+    Err(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+            ImageFormatHint::Name(String::from("HEIC")),
+            UnsupportedErrorKind::Format(ImageFormatHint::Name(String::from("HEIC"))))))
+}
+
+// The EXIF orientation tag; 1 (i.e. no transformation) if there isn't one.
+pub fn read_orientation(path: &Path) -> u32 {
+    let file = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(_) => return 1
+    };
+    match Reader::new().read_from_container(&mut BufReader::new(file)) {
+        Ok(exif) => exif.get_field(Tag::Orientation, In::PRIMARY)
+                        .and_then(|f| f.value.get_uint(0))
+                        .unwrap_or(1),
+        Err(_) => 1
+    }
+}
+
+// Orientations 5 to 8 swap the width and height.
+pub fn is_transposed(orientation: u32) -> bool {
+    orientation >= 5 && orientation <= 8
+}
+
+pub fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image
+    }
 }
 
 pub const THUMBNAIL_SIZE: u32 = 100;
 
+// AVIF is slow to encode; this favours speed over size.
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Jpeg,
+    WebP,
+    Avif
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<OutputFormat> {
+        match name.to_lowercase().as_str() {
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
+            "webp"         => Some(OutputFormat::WebP),
+            "avif"         => Some(OutputFormat::Avif),
+            _ => None
+        }
+    }
+
+    // AVIF encoding needs the 'avif' feature.
+    pub fn is_available(&self) -> bool {
+        *self != OutputFormat::Avif || cfg!(feature = "avif")
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Avif => "image/avif"
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif"
+        }
+    }
+}
+
+// How resized images are resampled and encoded.
+#[derive(Debug, Clone, Copy)]
+pub struct ResizeOptions {
+    pub quality: u8,
+    pub filter: FilterType,
+    pub format: OutputFormat
+}
+
+impl Default for ResizeOptions {
+    fn default() -> ResizeOptions {
+        ResizeOptions { quality: 75, filter: FilterType::Gaussian, format: OutputFormat::Jpeg }
+    }
+}
+
+impl ResizeOptions {
+    // Distinguishes images resized with different options, e.g. 'q75-gaussian-jpg'.
+    pub fn tag(&self) -> String {
+        format!("q{}-{}-{}", self.quality, filter_name(self.filter), self.format.extension())
+    }
+}
+
+pub fn parse_filter(name: &str) -> Option<FilterType> {
+    match name.to_lowercase().as_str() {
+        "nearest"    => Some(FilterType::Nearest),
+        "triangle"   => Some(FilterType::Triangle),
+        "catmullrom" => Some(FilterType::CatmullRom),
+        "gaussian"   => Some(FilterType::Gaussian),
+        "lanczos3"   => Some(FilterType::Lanczos3),
+        _ => None
+    }
+}
+
+pub fn filter_name(filter: FilterType) -> &'static str {
+    match filter {
+        FilterType::Nearest    => "nearest",
+        FilterType::Triangle   => "triangle",
+        FilterType::CatmullRom => "catmullrom",
+        FilterType::Gaussian   => "gaussian",
+        FilterType::Lanczos3   => "lanczos3"
+    }
+}
+
 pub fn as_scaled(file_name: &String) -> String {
     return format!("{}.scaled.mp4", file_name);
 }
@@ -109,7 +556,19 @@ pub fn as_preview(file_name: &String) -> String {
 }
 
 pub fn is_jpg(file_name: &String) -> bool {
-    return file_name.ends_with(".jpg") || file_name.ends_with(".JPG");
+    return MediaKind::from_path(file_name) == Some(MediaKind::Jpeg);
+}
+
+pub fn is_heic(file_name: &String) -> bool {
+    return MediaKind::from_path(file_name) == Some(MediaKind::Heic);
+}
+
+// Any still image that can be a gallery original.
+pub fn is_photo(file_name: &String) -> bool {
+    return match MediaKind::from_path(file_name) {
+        Some(kind) => kind != MediaKind::Video,
+        None => false
+    };
 }
 
 pub fn is_mp4(file_name: &String) -> bool {
@@ -118,23 +577,56 @@ pub fn is_mp4(file_name: &String) -> bool {
            file_name.ends_with(".avi") || file_name.ends_with(".AVI");
 }
 
-static TMP_FILE: &str = "/tmp/out.jpg";
+// Reports progress of work done in parallel, e.g. '[3/10] ...', in order of completion.
+pub struct Progress {
+    total: usize,
+    done: Mutex<usize>
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Progress {
+        Progress { total, done: Mutex::new(0) }
+    }
+
+    pub fn report(&self, msg: &str) {
+        // Printed under the lock so the counts are in order
+        let mut done = self.done.lock().unwrap();
+        *done += 1;
+        println!("[{}/{}] {}", *done, self.total, msg);
+    }
+}
+
+const MAX_ROWS_IN_JPG: usize = 0x10000;  // jpgs can have at most 64K rows
+const MAX_TN_COUNT: usize = MAX_ROWS_IN_JPG / THUMBNAIL_SIZE as usize;
+
+fn thumbnails_file_name(img_no: usize) -> String {
+    let postfix = if img_no > 0 { img_no.to_string() }  else { String::new() };
+    format!("thumbnails{}.jpg", postfix)
+}
 
 /**
-  Makes the thumbnails and previews for MP4s
+  Makes the thumbnails and previews for MP4s. 'previous' has, for each image, its index in the
+  metadata the existing thumbnails were made from if it's unchanged since, so that a thumbnails
+  file with the same items can be kept as it is and a video's preview needn't be regenerated.
+  Otherwise thumbnails are made from the originals, as re-encoding the old ones would lose a
+  little more quality each time.
 */
-pub fn make_preview(images: &Vec<Image>) {
-    const MAX_ROWS_IN_JPG: usize = 0x10000;  // jpgs can have at most 64K rows
-    const MAX_TN_COUNT: usize = MAX_ROWS_IN_JPG / THUMBNAIL_SIZE as usize;
-    
+pub fn make_preview(images: &Vec<Image>, previous: &[Option<usize>], options: &ResizeOptions) {
+    let progress = Progress::new(images.len());
+
     let mut start: usize = 0;
     let mut end: usize = min(images.len(), MAX_TN_COUNT);
 
     let mut img_no = 0;
     loop {
-        let postfix = if img_no > 0 { img_no.to_string() }  else { String::new() };
-        let file_name = format!("thumbnails{}.jpg", postfix);
-        make_preview_from_range(images, start, end, &file_name);
+        let file_name = thumbnails_file_name(img_no);
+        if is_unchanged_range(images, previous, start, end, &file_name) {
+            for image in &images[start .. end] {
+                progress.report(&format!("Reused thumbnail for {}", &image.path));
+            }
+        } else {
+            make_preview_from_range(images, previous, start, end, &file_name, options, &progress);
+        }
 
         if end == images.len() {
             break;
@@ -143,87 +635,246 @@ pub fn make_preview(images: &Vec<Image>) {
         end = min(images.len() - start, MAX_TN_COUNT) + start;
         img_no += 1;
     }
+
+    // There may be fewer items than before
+    img_no += 1;
+    while fs::remove_file(thumbnails_file_name(img_no)).is_ok() {
+        img_no += 1;
+    }
+}
+
+// Whether the existing thumbnails file has the same items in the same places, and the videos
+// still have their previews.
+fn is_unchanged_range(images: &Vec<Image>, previous: &[Option<usize>], start: usize, end: usize, file_name: &String) -> bool {
+    let same_items = (start .. end).all(|i| previous.get(i).copied().flatten() == Some(i));
+    let same_size = image::image_dimensions(file_name).map(|d| d == (THUMBNAIL_SIZE, THUMBNAIL_SIZE * (end - start) as u32)).unwrap_or(false);
+    let has_previews = images[start .. end].iter().all(|i| !is_mp4(&i.path) || Path::new(&as_preview(&i.path)).exists());
+
+    same_items && same_size && has_previews
 }
 
 // A jpg can only have 2^16 rows, so we create multiple thumbnail jpgs if necessary
-fn make_preview_from_range(images: &Vec<Image>, start: usize, end: usize, file_name: &String)
+fn make_preview_from_range(images: &Vec<Image>, previous: &[Option<usize>], start: usize, end: usize,
+                           file_name: &String, options: &ResizeOptions, progress: &Progress)
 {
+    // Decoding is the slow part, so that's done in parallel and the thumbnails are then copied in order
+    let thumbnails: Vec<Option<RgbaImage>> = (start .. end).into_par_iter()
+        .map(|i| make_thumbnail(&images[i], previous.get(i).copied().flatten(), options, progress))
+        .collect();
+
     let mut buffer: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(THUMBNAIL_SIZE as u32, THUMBNAIL_SIZE * (end - start) as u32);
-    for i in start .. end {
-        println!("Making thumbnail for {}", &images[i].path);
-        let image = if is_jpg(&images[i].path) {
-                        read_image(Path::new(&images[i].path))
-                    } else {
-                        match Command::new("ffmpeg").args(["-y", "-i", &images[i].path,
-                                                                "-ss", "00:00:01",
-                                                                "-vframes", "1",
-                                                                "-update", "true", &TMP_FILE]).output() {
-                            Ok(output) => {
-                                if !output.status.success() {
-                                    eprintln!("Making preview for {} failed with {}\n{}", images[i].path, 
-                                                                                          String::from_utf8(output.stdout).unwrap(), 
-                                                                                          String::from_utf8(output.stderr).unwrap());
-                                    continue;
-                                }
-                                read_image(Path::new(&TMP_FILE))
-                            },
-                            Err(e) => {
-                                eprintln!("Making preview for {} failed with error {}", &images[i].path, e);
-                                continue;
-                            }
-
-                    }
-        };
+    for (n, tn) in thumbnails.iter().enumerate() {
+        if let Some(tn) = tn {
+            buffer.copy_from(tn, 0, n as u32 * THUMBNAIL_SIZE).expect("Copying bits failed?");
+        }
+    }
 
-        match image {
-            Ok(img) => {
-                let tn = thumbnail(&img, THUMBNAIL_SIZE as u32, THUMBNAIL_SIZE as u32);
-                buffer.copy_from(&tn, 0, (i - start) as u32 * THUMBNAIL_SIZE).expect("Copying bits failed?");
+    let file = fs::File::create(file_name.as_str()).expect("Failed to create thumbnails");
+    buffer.write_with_encoder(JpegEncoder::new_with_quality(BufWriter::new(file), options.quality)).expect("Failed to save thumbnails");
+}
 
-                if is_mp4(&images[i].path) {
-                    make_mp4_preview(&images[i]);
-                }
-            }
+// Also makes the preview of a video, unless it's unchanged and has one.
+fn make_thumbnail(image: &Image, previous: Option<usize>, options: &ResizeOptions, progress: &Progress) -> Option<RgbaImage> {
+    if is_photo(&image.path) {
+        return match read_image(Path::new(&image.path)) {
+            Ok(img) => {
+                progress.report(&format!("Made thumbnail for {}", &image.path));
+                Some(thumbnail(&img, THUMBNAIL_SIZE as u32, THUMBNAIL_SIZE as u32))
+            },
             Err(e) => {
-                println!("Unable make thumbnail for {} with error {}", &images[i].path, e);
-                continue;
+                progress.report(&format!("Unable make thumbnail for {} with error {}", &image.path, e));
+                None
             }
+        };
+    }
+
+    // The preview is the frame, so there's no need to grab it again
+    if previous.is_some() {
+        if let Ok(img) = read_image(Path::new(&as_preview(&image.path))) {
+            progress.report(&format!("Made thumbnail for {} from its preview", &image.path));
+            return Some(thumbnail(&img, THUMBNAIL_SIZE as u32, THUMBNAIL_SIZE as u32));
         }
+    }
 
+    let frame = match grab_frame(&image.path) {
+        Ok(f) => f,
+        Err(e) => {
+            progress.report(&format!("Unable to make thumbnail for {} with error {}", &image.path, e));
+            return None;
+        }
+    };
+
+    match image::load_from_memory_with_format(&frame, ImageFormat::Jpeg) {
+        Ok(img) => {
+            if let Err(e) = make_mp4_preview(image, &frame, &img, options) {
+                eprintln!("Unable to save the preview for {} with error {}", &image.path, e);
+            }
+            progress.report(&format!("Made thumbnail and preview for {}", &image.path));
+            Some(thumbnail(&img, THUMBNAIL_SIZE as u32, THUMBNAIL_SIZE as u32))
+        },
+        Err(e) => {
+            progress.report(&format!("Unable make thumbnail for {} with error {}", &image.path, e));
+            None
+        }
     }
+}
+
+// Returns a jpg of the frame 1s into the video. ffmpeg writes it to stdout so there are no
+// temporary files to clean up or share between runs.
+// ffmpeg would take a name starting with '-' as an option, or with 'name:' as a protocol
+pub fn file_arg(path: &str) -> String {
+    format!("file:{}", path)
+}
 
-    buffer.save(file_name.as_str()).expect("Failed to save thumbnails");
+fn grab_frame(path: &String) -> Result<Vec<u8>, String> {
+    let output = Command::new("ffmpeg").args(["-i", &file_arg(path),
+                                              "-ss", "00:00:01",
+                                              "-vframes", "1",
+                                              "-f", "image2pipe",
+                                              "-vcodec", "mjpeg", "-"]).output()
+                                       .map_err(|e| e.to_string())?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+
+    Ok(output.stdout)
 }
 
-fn make_mp4_preview(image: &Image) {
-    let p = as_preview(&image.path);
-    let path = Path::new(p.as_str());
+// 'jpg' is the encoded frame, used as is if it's small enough.
+fn make_mp4_preview(image: &Image, jpg: &[u8], frame: &DynamicImage, options: &ResizeOptions) -> std::io::Result<()> {
     let size = image.height as f64 * image.width as f64;
     let preview_percent = (1920.0 * 1080.0) / size;
-    if preview_percent < 1.0 {
-        let mut buffer = Vec::<u8>::new();
-        resize_image(Path::new(TMP_FILE),
-                        (image.width as f64 * preview_percent.sqrt()).floor() as u32,
-                        (image.height as f64 * preview_percent.sqrt()).floor() as u32,
-                        &mut buffer);
-
-        let _ = fs::write(&path, buffer);                                              
+
+    let mut buffer = Vec::<u8>::new();
+    let preview = if preview_percent < 1.0 {
+        // Previews are always jpg, whatever the photos are served as
+        let options = ResizeOptions { format: OutputFormat::Jpeg, ..*options };
+        resize_decoded(frame,
+                       (image.width as f64 * preview_percent.sqrt()).floor() as u32,
+                       (image.height as f64 * preview_percent.sqrt()).floor() as u32,
+                       &options,
+                       &mut buffer)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        buffer.as_slice()
     } else {
-        let _ = fs::copy(&TMP_FILE, path);
+        jpg
+    };
+
+    // Write to a temporary file next to the preview and rename so a failed write never
+    // leaves a partial preview
+    let path = as_preview(&image.path);
+    let tmp = format!("{}.{}.tmp", path, Uuid::new_v4());
+    let result = fs::write(&tmp, preview).and_then(|_| fs::rename(&tmp, &path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
     }
+
+    result
 }
 
-pub fn resize_image(path: &Path, width: u32, height: u32, buffer: &mut dyn Write) {
+pub fn resize_image(path: &Path, width: u32, height: u32, options: &ResizeOptions, buffer: &mut dyn Write) {
     let image = read_image(path).expect("Unable to read image");
+    if let Err(e) = resize_decoded(&image, width, height, options, buffer) {
+        eprintln!("Unable to encode {} as {} with error {}", path.display(), options.format.extension(), e);
+    }
+}
+
+// Resizes to fit within width x height, but never larger than the original.
+fn resize_decoded(image: &DynamicImage, width: u32, height: u32, options: &ResizeOptions, buffer: &mut dyn Write) -> ImageResult<()> {
     let mut size_percent = f64::min(width as f64 / image.width() as f64, height as f64 / image.height() as f64);
     
     if size_percent >= 1.0 {
         size_percent = 1.0;
     }
 
-    let resized = resize(&image, (image.width() as f64 * size_percent).floor() as u32,
-                                 (image.height() as f64 * size_percent).floor() as u32, FilterType::Gaussian);
+    let resized = resize(image, (image.width() as f64 * size_percent).floor() as u32,
+                                (image.height() as f64 * size_percent).floor() as u32, options.filter);
+
+    encode(&resized, options, buffer)
+}
+
+fn encode(image: &RgbaImage, options: &ResizeOptions, buffer: &mut dyn Write) -> ImageResult<()> {
+    match options.format {
+        OutputFormat::Jpeg => image.write_with_encoder(JpegEncoder::new_with_quality(buffer, options.quality)),
+        OutputFormat::WebP => {
+            let webp = webp::Encoder::from_rgba(image.as_raw(), image.width(), image.height()).encode(options.quality as f32);
+            buffer.write_all(&webp).map_err(ImageError::IoError)
+        },
+        #[cfg(feature = "avif")]
+        OutputFormat::Avif => image.write_with_encoder(AvifEncoder::new_with_speed_quality(buffer, AVIF_SPEED, options.quality)),
+        // rust_gallery_formats only accepts avif with the feature
+        #[cfg(not(feature = "avif"))]
+        OutputFormat::Avif => Err(ImageError::IoError(std::io::Error::new(std::io::ErrorKind::Unsupported, "AVIF encoding needs the avif feature")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ITEMS: &str = r#"[{ "path": "IMG_1.jpg", "caption": "Harbour", "time": "2023-06-01T12:30:00",
+                              "width": 4032, "height": 3024, "mp4_scaled": false, "location": null }]"#;
 
-    let _ = resized.write_with_encoder(JpegEncoder::new(buffer));
+    // A metadata file with 'contents' in a directory of its own
+    fn write_test_metadata(name: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust_gallery_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(MD_FILE);
+        fs::write(&path, contents).unwrap();
+
+        path
+    }
 
+    #[test]
+    fn loads_a_bare_array_as_version_1() {
+        let path = write_test_metadata("v1", ITEMS);
+        let gallery = load_metadata(&path).unwrap();
+        assert_eq!(gallery.version, METADATA_VERSION);
+        assert_eq!(gallery.gallery.title, None);
+        assert_eq!(gallery.items.len(), 1);
+        assert_eq!(gallery.items[0].path, "IMG_1.jpg");
+        assert_eq!(gallery.items[0].caption, "Harbour");
+        assert_eq!(gallery.items[0].size, 0);
+    }
+
+    #[test]
+    fn loads_the_current_version() {
+        let contents = format!(r#"{{ "version": {}, "gallery": {{ "title": "Iceland" }}, "items": {} }}"#, METADATA_VERSION, ITEMS);
+        let path = write_test_metadata("current", &contents);
+        let gallery = load_metadata(&path).unwrap();
+        assert_eq!(gallery.version, METADATA_VERSION);
+        assert_eq!(gallery.gallery.title.as_deref(), Some("Iceland"));
+        assert_eq!(gallery.items[0].caption, "Harbour");
+    }
+
+    #[test]
+    fn rejects_metadata_without_a_version() {
+        let contents = format!(r#"{{ "gallery": {{}}, "items": {} }}"#, ITEMS);
+        let path = write_test_metadata("no_version", &contents);
+        let e = load_metadata(&path).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("no version"));
+    }
+
+    #[test]
+    fn rejects_a_future_version() {
+        let contents = format!(r#"{{ "version": {}, "gallery": {{}}, "items": {} }}"#, METADATA_VERSION + 1, ITEMS);
+        let path = write_test_metadata("future", &contents);
+        let e = load_metadata(&path).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("upgrade rust_gallery"));
+    }
+
+    #[test]
+    fn migrates_a_version_at_a_time() {
+        let v1: serde_json::Value = serde_json::from_str(ITEMS).unwrap();
+        let migrated = migrate(v1.clone()).unwrap();
+        assert_eq!(migrated["version"], METADATA_VERSION);
+        assert_eq!(migrated["items"], v1);
+
+        let current = serde_json::json!({ "version": METADATA_VERSION, "gallery": {}, "items": [] });
+        assert_eq!(migrate(current.clone()).unwrap(), current);
+        assert!(migrate(serde_json::json!({ "version": 0, "items": [] })).is_err());
+        assert!(migrate(serde_json::json!({ "version": "2", "items": [] })).is_err());
+    }
 }